@@ -1,10 +1,13 @@
 use std::ops::Deref;
 
-use syn::{Attribute, Error, Field, MetaList, NestedMeta};
+use syn::{
+    parenthesized, parse::ParseStream, token::Paren, Attribute, Error, Field, GenericArgument,
+    Ident, Lit, LitStr, Meta, MetaList, NestedMeta, Path, PathArguments, Token, Type,
+};
 
 pub fn find_attribute(attributes: &[Attribute]) -> Option<&Attribute> {
     attributes
-        .into_iter()
+        .iter()
         .find(|attr| attr.path.is_ident("datacache"))
 }
 
@@ -25,33 +28,216 @@ pub fn filter_attributes<A: Deref<Target = Attribute>>(
 #[derive(Clone)]
 pub struct FieldAttr {
     pub queryable: bool,
+    pub references: bool,
+    /// Name of the Query variant, defaults to the field name
+    pub rename: Option<Ident>,
+    /// Transform applied to the field before it is stored in the query
+    pub with: Option<Path>,
+    /// Store the key as `Arc<str>`, so cloning the query never copies the string. Only allowed
+    /// on `Arc<str>` fields or with `with`, a `String` field would be copied into a new `Arc`
+    /// for every query
+    pub shared: bool,
+    pub normalize: Option<Normalize>,
+    /// Reported by the generated `is_unique`, `storage!` checks it against its `unique(...)`
+    pub unique: bool,
+    /// The id of the entity, implies a unique queryable field
+    pub id: bool,
 }
 
+/// Normalization of a query key, applied when creating queries from an entity and in the
+/// generated `by_<field>` and `<field>_normalized` constructors.
+#[derive(Clone)]
+pub enum Normalize {
+    Lowercase,
+    Trim,
+    With(Path),
+}
+
+/// Parses `#[datacache(queryable(with = path, shared, unique), rename = "name", references)]`.
+///
+/// `parse_meta` only allows literals as values, which rules out `with = path`.
 pub fn field_attr(field: &Field) -> Result<FieldAttr, Error> {
-    let mut field_data = FieldAttr { queryable: false };
+    let mut field_data = FieldAttr {
+        queryable: false,
+        references: false,
+        rename: None,
+        with: None,
+        shared: false,
+        normalize: None,
+        unique: false,
+        id: false,
+    };
     let attr = match find_attribute(&field.attrs) {
         Some(attr) => attr,
         None => return Ok(field_data),
     };
-    let meta = get_meta_list(attr)?;
-    for nested in meta.nested {
-        if let NestedMeta::Meta(meta) = nested {
-            match meta {
-                syn::Meta::Path(path) => match path.get_ident() {
-                    Some(ident) => match ident.to_string().as_str() {
-                        "queryable" => field_data.queryable = true,
+    attr.parse_args_with(|input: ParseStream| {
+        while !input.is_empty() {
+            let ident: Ident = input.parse()?;
+            match ident.to_string().as_str() {
+                "queryable" => {
+                    field_data.queryable = true;
+                    if input.peek(Paren) {
+                        let content;
+                        parenthesized!(content in input);
+                        queryable_options(&content, &mut field_data)?;
+                    }
+                }
+                "references" => field_data.references = true,
+                "id" => {
+                    field_data.id = true;
+                    field_data.queryable = true;
+                    field_data.unique = true;
+                }
+                "rename" => {
+                    input.parse::<Token![=]>()?;
+                    field_data.rename = Some(input.parse::<LitStr>()?.parse()?);
+                }
+                other => {
+                    return Err(Error::new_spanned(
+                        &ident,
+                        format!("unsupported attribute ({other})"),
+                    ))
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(())
+    })?;
+    if !field_data.queryable
+        && (field_data.rename.is_some() || field_data.with.is_some() || field_data.shared)
+    {
+        return Err(Error::new_spanned(
+            attr,
+            "query options require the field to be queryable",
+        ));
+    }
+    if field_data.shared && field_data.with.is_none() && !is_shared_str(&field.ty) {
+        return Err(Error::new_spanned(
+            &field.ty,
+            "`shared` requires an `Arc<str>` field or a `with` transform producing the key",
+        ));
+    }
+    Ok(field_data)
+}
+
+fn queryable_options(input: ParseStream, field_data: &mut FieldAttr) -> Result<(), Error> {
+    while !input.is_empty() {
+        let ident: Ident = input.parse()?;
+        match ident.to_string().as_str() {
+            "with" => {
+                input.parse::<Token![=]>()?;
+                field_data.with = Some(input.parse()?);
+            }
+            "shared" => field_data.shared = true,
+            "unique" => field_data.unique = true,
+            "normalize" => {
+                input.parse::<Token![=]>()?;
+                field_data.normalize = Some(if input.peek(LitStr) {
+                    let lit: LitStr = input.parse()?;
+                    match lit.value().as_str() {
+                        "lowercase" => Normalize::Lowercase,
+                        "trim" => Normalize::Trim,
                         other => {
                             return Err(Error::new_spanned(
-                                ident,
-                                format!("unsupported attribute ({other})"),
+                                &lit,
+                                format!("unsupported normalization ({other})"),
                             ))
                         }
-                    },
-                    None => return Err(Error::new_spanned(path, "unsupported attribute")),
-                },
-                _ => return Err(Error::new_spanned(meta, "unsupported attribute")),
+                    }
+                } else {
+                    Normalize::With(input.parse()?)
+                });
+            }
+            other => {
+                return Err(Error::new_spanned(
+                    &ident,
+                    format!("unsupported queryable option ({other})"),
+                ))
             }
         }
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
     }
-    Ok(field_data)
+    Ok(())
+}
+
+/// A composite query over multiple fields, declared with
+/// `#[datacache(query(name = "tenant_slug", fields(tenant_id, slug)))]` on the struct.
+pub struct CompositeQuery {
+    pub name: Ident,
+    pub fields: Vec<Ident>,
+}
+
+pub fn composite_queries(attributes: &[Attribute]) -> Result<Vec<CompositeQuery>, Error> {
+    let mut queries = Vec::new();
+    for attr in attributes
+        .iter()
+        .filter(|attr| attr.path.is_ident("datacache"))
+    {
+        for nested in get_meta_list(attr)?.nested {
+            match nested {
+                NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("query") => {
+                    queries.push(composite_query(list)?)
+                }
+                other => return Err(Error::new_spanned(other, "unsupported attribute")),
+            }
+        }
+    }
+    Ok(queries)
+}
+
+fn composite_query(list: MetaList) -> Result<CompositeQuery, Error> {
+    let mut name = None;
+    let mut fields = Vec::new();
+    for nested in &list.nested {
+        match nested {
+            NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("name") => {
+                match &value.lit {
+                    Lit::Str(lit) => name = Some(lit.parse()?),
+                    other => return Err(Error::new_spanned(other, "expected a string")),
+                }
+            }
+            NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("fields") => {
+                for field in &list.nested {
+                    match field {
+                        NestedMeta::Meta(Meta::Path(path)) if path.get_ident().is_some() => {
+                            fields.push(path.get_ident().unwrap().clone())
+                        }
+                        other => return Err(Error::new_spanned(other, "expected a field name")),
+                    }
+                }
+            }
+            other => return Err(Error::new_spanned(other, "unsupported attribute")),
+        }
+    }
+    let name = name.ok_or_else(|| Error::new_spanned(&list, "missing query name"))?;
+    if fields.len() < 2 {
+        return Err(Error::new_spanned(
+            &list,
+            "composite queries need at least two fields",
+        ));
+    }
+    Ok(CompositeQuery { name, fields })
+}
+
+/// Whether the type is `Arc<str>`, which `shared` keys can be cloned from without copying.
+fn is_shared_str(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    let Some(segment) = path.path.segments.last() else {
+        return false;
+    };
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return false;
+    };
+    segment.ident == "Arc"
+        && matches!(
+            arguments.args.first(),
+            Some(GenericArgument::Type(Type::Path(inner))) if inner.path.is_ident("str")
+        )
 }
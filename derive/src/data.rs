@@ -1,14 +1,18 @@
 use proc_macro2::{Span, TokenStream};
-use quote::{quote, ToTokens};
-use syn::{Data, DeriveInput, Error, Field, Ident};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::spanned::Spanned;
+use syn::{
+    Attribute, Data, DataEnum, DeriveInput, Error, Field, GenericArgument, Ident, Index, Member,
+    PathArguments, Type, Visibility,
+};
 
-use crate::attr::{field_attr, filter_attributes, FieldAttr};
+use crate::attr::{composite_queries, field_attr, filter_attributes, FieldAttr, Normalize};
 
 #[derive(Clone)]
 struct QueryableField<'a> {
     idx: usize,
     field: Field,
-    _data: FieldAttr,
+    data: FieldAttr,
     struct_ident: &'a Ident,
 }
 
@@ -28,19 +32,20 @@ impl<'a> ToTokens for QueryableField<'a> {
     }
 }
 
+struct ReferenceField {
+    member: Member,
+    name: Ident,
+    vis: Visibility,
+    ty: Type,
+}
+
 #[repr(transparent)]
 struct EnumField<'a>(QueryableField<'a>);
 
 impl<'a> ToTokens for EnumField<'a> {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        let ident = match self.0.field.ident.clone() {
-            Some(ident) => ident,
-            None => {
-                let idx = self.0.idx;
-                return quote!(#idx(self.#idx.clone())).to_tokens(tokens);
-            }
-        };
-        let ty = &self.0.field.ty;
+        let ident = query_variant(&self.0.data, self.0.field.ident.as_ref(), self.0.idx);
+        let ty = query_type(&self.0.data, &self.0.field.ty);
         quote!(#ident(#ty)).to_tokens(tokens)
     }
 }
@@ -49,34 +54,65 @@ struct EnumCreateField<'a>(QueryableField<'a>);
 
 impl<'a> ToTokens for EnumCreateField<'a> {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        let ident = match self.0.field.ident.clone() {
-            Some(ident) => ident,
-            None => Ident::new(&format!("F{}", self.0.idx), Span::call_site()),
+        let ident = query_variant(&self.0.data, self.0.field.ident.as_ref(), self.0.idx);
+        let value = match self.0.field.ident.clone() {
+            Some(field) => query_value(&self.0.data, quote!(self.#field)),
+            None => {
+                let idx = Index::from(self.0.idx);
+                query_value(&self.0.data, quote!(self.#idx))
+            }
         };
-        quote!(#ident(self.#ident.clone())).to_tokens(tokens)
+        quote!(#ident(#value)).to_tokens(tokens)
     }
 }
 
 pub fn derive(input: DeriveInput) -> Result<TokenStream, syn::Error> {
     let data = match input.data {
         Data::Struct(data) => data,
-        _ => {
-            return Err(Error::new(
-                Span::call_site(),
-                "enums/unions are not supported",
-            ))
-        }
+        Data::Enum(data) => return derive_enum(&input.vis, &input.ident, &input.attrs, data),
+        Data::Union(_) => return Err(Error::new(Span::call_site(), "unions are not supported")),
     };
+    let composites = composite_queries(&input.attrs)?;
+    let mut composite_fields = vec![Vec::new(); composites.len()];
     let mut fields = Vec::new();
-    let mut f_idx = 0;
-    for field in data.fields.into_iter() {
+    let mut references = Vec::new();
+    let mut id = None;
+    for (f_idx, field) in data.fields.into_iter().enumerate() {
         let attr = field_attr(&field)?;
-        f_idx += 1;
+        for (composite, found) in composites.iter().zip(composite_fields.iter_mut()) {
+            for name in &composite.fields {
+                if field.ident.as_ref() == Some(name) {
+                    found.push((name.clone(), field.ty.clone(), attr.clone()));
+                }
+            }
+        }
+        if attr.references || is_reference_type(&field.ty) {
+            let member = match field.ident.clone() {
+                Some(ident) => Member::Named(ident),
+                None => Member::Unnamed(Index::from(f_idx)),
+            };
+            let name = match field.ident.clone() {
+                Some(ident) => ident,
+                None => Ident::new(&format!("f{f_idx}"), Span::call_site()),
+            };
+            references.push(ReferenceField {
+                member,
+                name,
+                vis: field.vis.clone(),
+                ty: field.ty.clone(),
+            });
+        }
+        if attr.id {
+            if id.is_some() {
+                return Err(Error::new_spanned(&field, "only one field can be the id"));
+            }
+            id = Some(fields.len());
+        }
         if attr.queryable {
             fields.push(QueryableField {
                 idx: f_idx,
                 field,
-                _data: attr,
+                data: attr,
                 struct_ident: &input.ident,
             });
         }
@@ -85,23 +121,151 @@ pub fn derive(input: DeriveInput) -> Result<TokenStream, syn::Error> {
     let vis = input.vis;
     let ident = &input.ident;
 
-    #[cfg(not(feature = "query-serde"))]
-    let serde_derive = quote!();
-    #[cfg(feature = "query-serde")]
-    let serde_derive =
-        quote!(#[derive(datacache::__internal::Serialize, datacache::__internal::Deserialize)]);
+    let serde_derive = serde_derive();
 
     let enum_fields: Vec<EnumField> = fields.clone().into_iter().map(EnumField).collect();
     let query_ident = new_ident(&input.ident, "Query");
+    let mut queries: Vec<QueryInfo> = fields
+        .iter()
+        .map(|field| {
+            QueryInfo::new(
+                &field.data,
+                field.field.ident.as_ref(),
+                field.idx,
+                &field.field.ty,
+            )
+        })
+        .collect();
+    let mut composite_variants = Vec::new();
+    let mut composite_creates = Vec::new();
+    for (composite, found) in composites.iter().zip(composite_fields) {
+        let name = &composite.name;
+        let mut types = Vec::new();
+        let mut values = Vec::new();
+        let mut inputs = Vec::new();
+        let mut keys = Vec::new();
+        // Keep the order of the attribute instead of the order of declaration
+        for field in &composite.fields {
+            match found.iter().find(|(ident, _, _)| ident == field) {
+                Some((ident, ty, attr)) => {
+                    types.push(query_type(attr, ty));
+                    values.push(query_value(attr, quote!(self.#ident)));
+                    inputs.push(ty.to_token_stream());
+                    keys.push(owned_query_value(attr, input_ident(keys.len())));
+                }
+                None => {
+                    return Err(Error::new_spanned(
+                        field,
+                        format!("unknown field `{field}` in query `{name}`"),
+                    ))
+                }
+            }
+        }
+        composite_variants.push(quote!(#name(#(#types),*)));
+        queries.push(QueryInfo {
+            variant: name.clone(),
+            field: name.to_string(),
+            constructor: Ident::new(&format!("by_{name}"), Span::call_site()),
+            types,
+            inputs,
+            keys,
+            unique: true,
+        });
+        composite_creates.push(quote!(#query_ident::#name(#(#values),*)));
+    }
+    let constructors = fields.iter().filter_map(|field| {
+        normalized_constructor(
+            &vis,
+            &field.data,
+            field.field.ident.as_ref(),
+            field.idx,
+            &field.field.ty,
+        )
+    });
+    check_renames(&queries)?;
+    let helpers = query_helpers(&vis, ident, &query_ident, &queries);
     let query_enum = quote! {
         #[derive(Debug, Clone, PartialEq, Eq, Hash)]
         #serde_derive
         #[allow(non_camel_case_types)]
         #vis enum #query_ident {
             #(#enum_fields,)*
+            #(#composite_variants,)*
+        }
+
+        impl #query_ident {
+            #(#constructors)*
         }
+
+        #helpers
     };
+    let data_id = id.map(|id| {
+        let field = &fields[id];
+        let member = match field.field.ident.clone() {
+            Some(ident) => Member::Named(ident),
+            None => Member::Unnamed(Index::from(field.idx)),
+        };
+        let value = query_value(&field.data, quote!((*id)));
+        data_id(
+            ident,
+            &query_ident,
+            &field.data,
+            field.field.ident.as_ref(),
+            field.idx,
+            &field.field.ty,
+            vec![quote!(Self { #member: id, .. } => #value)],
+        )
+    });
     let fields: Vec<EnumCreateField> = fields.into_iter().map(EnumCreateField).collect();
+    let references_ident = new_ident(&input.ident, "References");
+    let (references_fn, populate) = if references.is_empty() {
+        (quote!(), quote!())
+    } else {
+        let members: Vec<_> = references.iter().map(|field| &field.member).collect();
+        let names: Vec<_> = references.iter().map(|field| &field.name).collect();
+        let field_vis = references.iter().map(|field| &field.vis);
+        let types: Vec<_> = references.iter().map(|field| &field.ty).collect();
+        // Nest the lookups as `join(a, join(b, ready(())))` so they are all polled concurrently
+        let mut joined = quote!(datacache::__internal::ready(()));
+        let mut pattern = quote!(());
+        for (member, name) in members.iter().zip(&names).rev() {
+            joined = quote!(datacache::__internal::join(datacache::Resolve::resolve(&self.#member, manager), #joined));
+            pattern = quote!((#name, #pattern));
+        }
+        let populate = quote! {
+            #vis struct #references_ident {
+                #(#field_vis #names: <#types as datacache::Resolvable>::Resolved,)*
+            }
+
+            impl datacache::Populate for #ident {
+                type References = #references_ident;
+            }
+
+            #[datacache::__internal::async_trait]
+            impl<M: ?Sized + Sync> datacache::PopulateWith<M> for #ident
+            where
+                #(#types: datacache::Resolve<M>,)*
+            {
+                async fn populate(&self, manager: &M) -> #references_ident {
+                    let #pattern = #joined.await;
+                    #references_ident { #(#names),* }
+                }
+            }
+        };
+        let references_fn = quote! {
+            fn references(&self) -> Vec<datacache::DataReference> {
+                let mut references = Vec::new();
+                #(datacache::References::collect_references(&self.#members, &mut references);)*
+                references
+            }
+            fn referenced_types() -> Vec<std::any::TypeId> {
+                let mut types = Vec::new();
+                #(<#types as datacache::References>::referenced_types(&mut types);)*
+                types
+            }
+        };
+        (references_fn, populate)
+    };
 
     let out = quote! {
         #query_enum
@@ -109,13 +273,474 @@ pub fn derive(input: DeriveInput) -> Result<TokenStream, syn::Error> {
         impl datacache::DataMarker for #ident {
             type Query = #query_ident;
             fn create_queries(&self) -> Vec<Self::Query> {
-                vec![#(#query_ident::#fields),*]
+                vec![#(#query_ident::#fields,)* #(#composite_creates,)*]
+            }
+            fn query_name(query: &Self::Query) -> &'static str {
+                query.field_name()
             }
+            #references_fn
         }
+
+        #data_id
+
+        #populate
     };
     Ok(out)
 }
 
+/// Enums get a single Query enum across all variants, a variant without a queryable field
+/// simply creates no query for it.
+///
+/// Fields of the same name share a query and need the same type. Queryable fields of tuple
+/// variants need a rename, their position is no name.
+fn derive_enum(
+    vis: &Visibility,
+    ident: &Ident,
+    attrs: &[Attribute],
+    data: DataEnum,
+) -> Result<TokenStream, Error> {
+    if let Some(composite) = composite_queries(attrs)?.first() {
+        return Err(Error::new_spanned(
+            &composite.name,
+            "composite queries are not supported on enums",
+        ));
+    }
+    let query_ident = new_ident(ident, "Query");
+    let mut query_fields: Vec<QueryInfo> = Vec::new();
+    let mut constructors = Vec::new();
+    let mut arms = Vec::new();
+    let mut id = None;
+    let mut id_arms = Vec::new();
+    let mut without_id = Vec::new();
+    for variant in data.variants {
+        let variant_ident = &variant.ident;
+        let mut has_id = false;
+        let mut bindings = Vec::new();
+        let mut queries = Vec::new();
+        for (f_idx, field) in variant.fields.into_iter().enumerate() {
+            let attr = field_attr(&field)?;
+            if attr.references {
+                return Err(Error::new_spanned(
+                    &field,
+                    "references are not supported on enum variants",
+                ));
+            }
+            if !attr.queryable {
+                continue;
+            }
+            if field.ident.is_none() && attr.rename.is_none() {
+                // The position alone would merge unrelated fields of different variants
+                return Err(Error::new_spanned(
+                    &field,
+                    "queryable fields of tuple variants need a rename",
+                ));
+            }
+            let name = query_variant(&attr, field.ident.as_ref(), f_idx);
+            let member = match field.ident.clone() {
+                Some(ident) => Member::Named(ident),
+                None => Member::Unnamed(Index::from(f_idx)),
+            };
+            let info = QueryInfo::new(&attr, field.ident.as_ref(), f_idx, &field.ty);
+            let ty = info.types[0].clone();
+            match query_fields.iter().find(|other| other.variant == name) {
+                Some(_) => {}
+                None => {
+                    constructors.extend(normalized_constructor(
+                        vis,
+                        &attr,
+                        field.ident.as_ref(),
+                        f_idx,
+                        &field.ty,
+                    ));
+                    query_fields.push(info)
+                }
+            }
+            let binding = Ident::new(
+                &format!("__{}", name.to_string().to_lowercase()),
+                Span::call_site(),
+            );
+            if attr.id {
+                match &id {
+                    Some((_, _, _, _, other)) if other != &name => {
+                        return Err(Error::new_spanned(
+                            &field,
+                            format!("the id has to be the `{other}` query in every variant"),
+                        ))
+                    }
+                    Some(_) => {}
+                    None => {
+                        id = Some((
+                            attr.clone(),
+                            field.ident.clone(),
+                            f_idx,
+                            field.ty.clone(),
+                            name.clone(),
+                        ))
+                    }
+                }
+                let value = query_value(&attr, quote!((*#binding)));
+                id_arms.push(quote!(Self::#variant_ident { #member: #binding, .. } => #value));
+                has_id = true;
+            }
+            bindings.push(quote!(#member: #binding));
+            let value = query_value(&attr, quote!((*#binding)));
+            // Fields sharing a query need the same type, a mismatch is reported at the field
+            let value = quote_spanned!(field.ty.span()=> { let value: #ty = #value; value });
+            queries.push(quote!(#query_ident::#name(#value)));
+        }
+        arms.push(quote!(Self::#variant_ident { #(#bindings,)* .. } => vec![#(#queries),*]));
+        if !has_id {
+            without_id.push(variant_ident.clone());
+        }
+    }
+    if let (Some(_), Some(variant)) = (&id, without_id.first()) {
+        return Err(Error::new_spanned(variant, "every variant needs an id"));
+    }
+    let data_id = id.map(|(attr, field, idx, ty, _)| {
+        data_id(
+            ident,
+            &query_ident,
+            &attr,
+            field.as_ref(),
+            idx,
+            &ty,
+            id_arms,
+        )
+    });
+
+    let serde_derive = serde_derive();
+    check_renames(&query_fields)?;
+    let helpers = query_helpers(vis, ident, &query_ident, &query_fields);
+    let names = query_fields.iter().map(|info| &info.variant);
+    let types = query_fields.iter().map(|info| &info.types[0]);
+    Ok(quote! {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        #serde_derive
+        #[allow(non_camel_case_types)]
+        #vis enum #query_ident {
+            #(#names(#types),)*
+        }
+
+        impl #query_ident {
+            #(#constructors)*
+        }
+
+        #helpers
+
+        impl datacache::DataMarker for #ident {
+            type Query = #query_ident;
+            fn create_queries(&self) -> Vec<Self::Query> {
+                match self {
+                    #(#arms,)*
+                }
+            }
+            fn query_name(query: &Self::Query) -> &'static str {
+                query.field_name()
+            }
+        }
+
+        #data_id
+    })
+}
+
+/// `datacache::DataId`, `arms` extract the id from a (variant of the) entity bound as `id`.
+fn data_id(
+    ident: &Ident,
+    query_ident: &Ident,
+    attr: &FieldAttr,
+    field: Option<&Ident>,
+    idx: usize,
+    ty: &Type,
+    arms: Vec<TokenStream>,
+) -> TokenStream {
+    let variant = query_variant(attr, field, idx);
+    let ty = query_type(attr, ty);
+    quote! {
+        impl datacache::DataId for #ident {
+            type Id = #ty;
+            fn id(&self) -> Self::Id {
+                match self {
+                    #(#arms,)*
+                }
+            }
+            fn id_query(id: Self::Id) -> Self::Query {
+                #query_ident::#variant(id)
+            }
+            #[allow(unreachable_patterns)]
+            fn query_id(query: &Self::Query) -> Option<Self::Id> {
+                match query {
+                    #query_ident::#variant(id) => Some(id.clone()),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// A variant of the generated Query enum.
+struct QueryInfo {
+    variant: Ident,
+    /// The name of the field or composite query
+    field: String,
+    constructor: Ident,
+    types: Vec<TokenStream>,
+    /// The field types taken by the constructor
+    inputs: Vec<TokenStream>,
+    /// The values of the variant, computed from the constructor arguments
+    keys: Vec<TokenStream>,
+    /// The id, composite queries and `queryable(unique)` fields
+    unique: bool,
+}
+
+impl QueryInfo {
+    fn new(attr: &FieldAttr, field: Option<&Ident>, idx: usize, ty: &Type) -> Self {
+        let name = match (field, &attr.rename) {
+            (Some(field), _) => field.to_string(),
+            (None, Some(rename)) => rename.to_string(),
+            (None, None) => idx.to_string(),
+        };
+        Self {
+            variant: query_variant(attr, field, idx),
+            field: name,
+            constructor: query_constructor(attr, field, idx),
+            types: vec![query_type(attr, ty)],
+            inputs: vec![ty.to_token_stream()],
+            keys: vec![owned_query_value(attr, input_ident(0))],
+            unique: attr.unique,
+        }
+    }
+}
+
+/// A rename must not shadow one of the generated constructors.
+fn check_renames(queries: &[QueryInfo]) -> Result<(), Error> {
+    for query in queries {
+        if queries
+            .iter()
+            .any(|other| other.constructor == query.variant)
+        {
+            return Err(Error::new_spanned(
+                &query.variant,
+                format!("`{}` is the name of a generated constructor", query.variant),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn query_constructor(attr: &FieldAttr, field: Option<&Ident>, idx: usize) -> Ident {
+    let constructor = match (field, &attr.rename) {
+        (Some(field), _) => format!("by_{field}"),
+        (None, Some(rename)) => format!("by_{rename}"),
+        (None, None) => format!("by_f{idx}"),
+    };
+    Ident::new(&constructor, Span::call_site())
+}
+
+fn input_ident(idx: usize) -> TokenStream {
+    Ident::new(&format!("value{idx}"), Span::call_site()).to_token_stream()
+}
+
+/// Constructors, introspection and `Display` for the Query enum.
+fn query_helpers(
+    vis: &Visibility,
+    ident: &Ident,
+    query_ident: &Ident,
+    queries: &[QueryInfo],
+) -> TokenStream {
+    let field_names: Vec<_> = queries.iter().map(|query| &query.field).collect();
+    let constructors = queries.iter().map(|query| {
+        let variant = &query.variant;
+        let constructor = &query.constructor;
+        let values: Vec<_> = (0..query.inputs.len()).map(input_ident).collect();
+        let inputs = &query.inputs;
+        let keys = &query.keys;
+        quote! {
+            #vis fn #constructor(#(#values: impl std::convert::Into<#inputs>),*) -> Self {
+                #(let #values: #inputs = #values.into();)*
+                Self::#variant(#(#keys),*)
+            }
+        }
+    });
+    let patterns: Vec<_> = queries
+        .iter()
+        .map(|query| {
+            let variant = &query.variant;
+            let values = (0..query.types.len())
+                .map(|idx| Ident::new(&format!("value{idx}"), Span::call_site()));
+            quote!(Self::#variant(#(ref #values),*))
+        })
+        .collect();
+    let variants: Vec<_> = queries.iter().map(|query| &query.variant).collect();
+    let unique = queries.iter().map(|query| query.unique);
+    // One constant per variant, which lets `storage!` check its `unique(...)` and `fields(...)`
+    let unique_consts: Vec<_> = variants
+        .iter()
+        .map(|variant| unique_const(variant))
+        .collect();
+    let formats = queries.iter().map(|query| {
+        let values =
+            (0..query.types.len()).map(|idx| Ident::new(&format!("value{idx}"), Span::call_site()));
+        let format = match query.types.len() {
+            1 => format!("{}={{:?}}", query.field),
+            len => format!("{}=({})", query.field, vec!["{:?}"; len].join(", ")),
+        };
+        quote!(write!(f, #format, #(#values),*))
+    });
+    quote! {
+        impl #query_ident {
+            #(#constructors)*
+
+            #vis fn field_name(&self) -> &'static str {
+                match *self {
+                    #(Self::#variants(..) => #field_names,)*
+                }
+            }
+
+            #(
+                #[doc(hidden)]
+                #[allow(non_upper_case_globals)]
+                #vis const #unique_consts: bool = #unique;
+            )*
+
+            #vis fn is_unique(&self) -> bool {
+                match *self {
+                    #(Self::#variants(..) => Self::#unique_consts,)*
+                }
+            }
+        }
+
+        impl std::fmt::Display for #query_ident {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match *self {
+                    #(#patterns => #formats,)*
+                }
+            }
+        }
+
+        impl #ident {
+            #vis fn query_fields() -> &'static [&'static str] {
+                &[#(#field_names),*]
+            }
+        }
+    }
+}
+
+/// Name of the constant telling whether a query variant is unique.
+pub(crate) fn unique_const(variant: &Ident) -> Ident {
+    Ident::new(&format!("__unique_{variant}"), variant.span())
+}
+
+fn query_variant(attr: &FieldAttr, field: Option<&Ident>, idx: usize) -> Ident {
+    match (&attr.rename, field) {
+        (Some(rename), _) => rename.clone(),
+        (None, Some(field)) => field.clone(),
+        (None, None) => Ident::new(&format!("F{idx}"), Span::call_site()),
+    }
+}
+
+fn query_type(attr: &FieldAttr, ty: &Type) -> TokenStream {
+    if attr.shared {
+        quote!(std::sync::Arc<str>)
+    } else {
+        ty.to_token_stream()
+    }
+}
+
+/// Builds the key of a query from the place expression of the field.
+fn query_value(attr: &FieldAttr, place: TokenStream) -> TokenStream {
+    let value = match &attr.with {
+        Some(with) => quote!(#with(&#place)),
+        None => quote!(#place.clone()),
+    };
+    key_value(attr, value)
+}
+
+/// Like [`query_value`] for an owned value of the field, used by the constructors.
+fn owned_query_value(attr: &FieldAttr, value: TokenStream) -> TokenStream {
+    let value = match &attr.with {
+        Some(with) => quote!(#with(&#value)),
+        None => value,
+    };
+    key_value(attr, value)
+}
+
+fn key_value(attr: &FieldAttr, value: TokenStream) -> TokenStream {
+    let value = if attr.shared {
+        quote!(std::sync::Arc::<str>::from(#value))
+    } else {
+        value
+    };
+    normalize_value(attr, value)
+}
+
+fn normalize_value(attr: &FieldAttr, value: TokenStream) -> TokenStream {
+    match &attr.normalize {
+        None => value,
+        Some(Normalize::Lowercase) => quote!({
+            let key = #value;
+            std::convert::From::from(std::convert::AsRef::<str>::as_ref(&key).to_lowercase())
+        }),
+        Some(Normalize::Trim) => quote!({
+            let key = #value;
+            std::convert::From::from(std::convert::AsRef::<str>::as_ref(&key).trim().to_owned())
+        }),
+        Some(Normalize::With(path)) => quote!(#path(&#value)),
+    }
+}
+
+/// `<field>_normalized(..)` builds a query from a raw value of the field, which goes through
+/// the same `with`, `shared` and `normalize` steps as the values of the entities, just like
+/// `by_<field>(..)`.
+fn normalized_constructor(
+    vis: &Visibility,
+    attr: &FieldAttr,
+    field: Option<&Ident>,
+    idx: usize,
+    ty: &Type,
+) -> Option<TokenStream> {
+    attr.normalize.as_ref()?;
+    let constructor = query_constructor(attr, field, idx);
+    let ident = match field {
+        Some(field) => new_ident(field, "_normalized"),
+        None => Ident::new(&format!("f{idx}_normalized"), Span::call_site()),
+    };
+    Some(quote! {
+        #vis fn #ident(key: impl std::convert::Into<#ty>) -> Self {
+            Self::#constructor(key)
+        }
+    })
+}
+
+fn serde_derive() -> TokenStream {
+    #[cfg(not(feature = "query-serde"))]
+    let serde_derive = quote!();
+    #[cfg(feature = "query-serde")]
+    let serde_derive =
+        quote!(#[derive(datacache::__internal::Serialize, datacache::__internal::Deserialize)]);
+    serde_derive
+}
+
+/// `DataRef<_>`, `Option<DataRef<_>>` and `Vec<DataRef<_>>`, recognized by name since the
+/// macro cannot resolve types. Aliases still need `#[datacache(references)]`.
+fn is_reference_type(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    let Some(segment) = path.path.segments.last() else {
+        return false;
+    };
+    match segment.ident.to_string().as_str() {
+        "DataRef" => true,
+        "Option" | "Vec" => match &segment.arguments {
+            PathArguments::AngleBracketed(arguments) => arguments.args.iter().any(
+                |argument| matches!(argument, GenericArgument::Type(ty) if is_reference_type(ty)),
+            ),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 fn new_ident(ident: &Ident, s: &'static str) -> Ident {
     Ident::new(&format!("{}{s}", ident), Span::call_site())
 }
@@ -1,17 +1,21 @@
 use proc_macro2::TokenStream;
-use quote::{quote, ToTokens};
+use quote::{quote, quote_spanned, ToTokens};
 use syn::{
     parenthesized,
     parse::{Parse, ParseStream},
     punctuated::Punctuated,
+    spanned::Spanned,
     token::{Colon, Comma},
-    Error, Ident, Token, TypePath, Visibility,
+    Error, Expr, Ident, Token, Type, TypePath, Visibility,
 };
 
+use crate::data::unique_const;
+
 mod kw {
     syn::custom_keyword!(fields);
     syn::custom_keyword!(unique);
     syn::custom_keyword!(id);
+    syn::custom_keyword!(config);
 }
 
 pub(crate) struct StorageArgs {
@@ -19,24 +23,59 @@ pub(crate) struct StorageArgs {
     ident: Ident,
     executor_path: TypePath,
     data_path: TypePath,
-    id_field: FieldTuple,
-    #[allow(dead_code)]
+    /// Taken from `datacache::DataId` when omitted
+    id_field: Option<FieldTuple>,
     unique_fields: Vec<StorageField>,
-    #[allow(dead_code)]
     query_fields: Vec<StorageField>,
+    config: Vec<ConfigField>,
 }
 
 pub(crate) struct StorageField(FieldTuple);
-pub(crate) struct UniqueField(FieldTuple);
 
-pub(crate) struct FieldTuple(Ident, TypePath);
+pub(crate) struct FieldTuple(Ident, Type);
 
-impl Parse for StorageField {
+pub(crate) struct ConfigField(Ident, Expr);
+
+impl Parse for ConfigField {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        Ok(Self(FieldTuple::parse(input)?))
+        let ident: Ident = input.parse()?;
+        match ident.to_string().as_str() {
+            "max_capacity"
+            | "time_to_live"
+            | "time_to_idle"
+            | "weigher"
+            | "batch_window"
+            | "negative_cache"
+            | "negative_time_to_live"
+            | "timeout"
+            | "backend" => {}
+            other => {
+                return Err(Error::new_spanned(
+                    ident,
+                    format!("unsupported config option ({other})"),
+                ))
+            }
+        }
+        input.parse::<Token![=]>()?;
+        Ok(Self(ident, input.parse()?))
+    }
+}
+
+impl ToTokens for ConfigField {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let ident = &self.0;
+        match &self.1 {
+            // Options with several arguments take a tuple, e.g. `timeout = (duration, sleep)`
+            Expr::Tuple(tuple) => {
+                let args = &tuple.elems;
+                quote!(.#ident(#args)).to_tokens(tokens)
+            }
+            expr => quote!(.#ident(#expr)).to_tokens(tokens),
+        }
     }
 }
-impl Parse for UniqueField {
+
+impl Parse for StorageField {
     fn parse(input: ParseStream) -> syn::Result<Self> {
         Ok(Self(FieldTuple::parse(input)?))
     }
@@ -58,6 +97,39 @@ impl ToTokens for StorageField {
     }
 }
 
+#[repr(transparent)]
+struct QueryMatchArm<'a>(&'a StorageField);
+
+impl<'a> ToTokens for QueryMatchArm<'a> {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let ident = &self.0 .0 .0;
+        let mut ty = &self.0 .0 .1;
+        // Types forwarded through `macro_rules!` arrive wrapped in an invisible group
+        while let Type::Group(group) = ty {
+            ty = &group.elem;
+        }
+        match ty {
+            // Composite queries are declared as `name: (A, B)`
+            Type::Tuple(tuple) => {
+                let values: Vec<_> = (0..tuple.elems.len())
+                    .map(|idx| Ident::new(&format!("value{idx}"), ident.span()))
+                    .collect();
+                let types = tuple.elems.iter();
+                quote!(Query::#ident(#(#values),*) => {
+                    let _: (#(&#types,)*) = (#(#values,)*);
+                    true
+                })
+                .to_tokens(tokens)
+            }
+            ty => quote!(Query::#ident(value) => {
+                let _: &#ty = value;
+                true
+            })
+            .to_tokens(tokens),
+        }
+    }
+}
+
 impl Parse for StorageArgs {
     fn parse(input: ParseStream) -> syn::Result<Self> {
         let vis: Visibility = input.parse()?;
@@ -71,16 +143,18 @@ impl Parse for StorageArgs {
 
         input.parse::<Token![,]>()?;
 
-        let id_field: FieldTuple = {
+        let id_field = if input.peek(kw::id) {
             input.parse::<kw::id>()?;
             let content;
             parenthesized!(content in input);
-            content.parse()?
+            let id_field: FieldTuple = content.parse()?;
+            input.parse::<Token![,]>()?;
+            Some(id_field)
+        } else {
+            None
         };
 
         let unique_fields: Punctuated<StorageField, Comma> = {
-            input.parse::<Token![,]>()?;
-
             input.parse::<kw::unique>()?;
             let content;
             parenthesized!(content in input);
@@ -96,6 +170,17 @@ impl Parse for StorageArgs {
 
             Punctuated::parse_terminated(&content)?
         };
+        let config: Punctuated<ConfigField, Comma> = if input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+
+            input.parse::<kw::config>()?;
+            let content;
+            parenthesized!(content in input);
+
+            Punctuated::parse_terminated(&content)?
+        } else {
+            Punctuated::new()
+        };
         Ok(Self {
             visibility: vis,
             ident,
@@ -104,6 +189,7 @@ impl Parse for StorageArgs {
             id_field,
             unique_fields: unique_fields.into_iter().collect(),
             query_fields: query_fields.into_iter().collect(),
+            config: config.into_iter().collect(),
         })
     }
 }
@@ -113,128 +199,109 @@ pub(crate) fn storage_expand(input: StorageArgs) -> Result<TokenStream, Error> {
         visibility: vis,
         ident,
         data_path,
-        id_field: FieldTuple(id_field, _),
+        id_field,
         executor_path,
-        unique_fields: _,
-        query_fields: _,
+        unique_fields,
+        query_fields,
+        config,
     } = input;
-    let out = quote! {
-        #[derive(Clone)]
-        #vis struct #ident {
-            executor: std::sync::Arc<#executor_path>,
-            data: datacache::__internal::moka::future::Cache<<#executor_path as datacache::DataQueryExecutor<#data_path>>::Id, datacache::Data<#data_path>>,
-            query_cache: datacache::__internal::moka::future::Cache<<#data_path as datacache::DataMarker>::Query, Option<datacache::Data<#data_path>>>,
-            query: std::sync::Arc<datacache::__internal::dashmap::DashMap<<#data_path as datacache::DataMarker>::Query, <#executor_path as datacache::DataQueryExecutor<#data_path>>::Id>>,
-        }
-
-        impl #ident {
-            pub fn new(executor: #executor_path) -> Self {
-                Self {
-                    executor: std::sync::Arc::new(executor),
-                    data: datacache::__internal::moka::future::Cache::builder().build(),
-                    query_cache: datacache::__internal::moka::future::Cache::builder().build(),
-                    query: std::sync::Arc::new(datacache::__internal::dashmap::DashMap::new()),
-                }
-            }
-
-            fn find_id(&self, query: &<#data_path as datacache::DataMarker>::Query) -> Option<<#executor_path as datacache::DataQueryExecutor<#data_path>>::Id> {
+    let (query_id, id_query) = match id_field {
+        Some(FieldTuple(id_field, _)) => (
+            quote! {
                 type Query = <#data_path as datacache::DataMarker>::Query;
                 match query {
                     Query::#id_field(id) => Some(id.clone()),
-                    other => self.query.get(other).map(|v| v.value().clone()),
-                }
-            }
-
-            async fn insert_data(&self, data: datacache::Data<#data_path>) {
-                for query in datacache::DataMarker::create_queries(&data) {
-                    self.query.insert(query, data.#id_field);
+                    _ => None,
                 }
-                self.data.insert(data.#id_field, data).await;
-            }
+            },
+            quote! {
+                type Query = <#data_path as datacache::DataMarker>::Query;
+                Query::#id_field(id.clone())
+            },
+        ),
+        None => {
+            // `IdSchema` requires the executor to agree with the entity on the id type
+            let id_schema = quote_spanned! {executor_path.span()=>
+                <datacache::IdSchema as datacache::StorageSchema<#executor_path, #data_path>>
+            };
+            (
+                quote!(#id_schema::query_id(query)),
+                quote!(#id_schema::id_query(id)),
+            )
         }
+    };
+    let unique_arms = unique_fields.iter().map(QueryMatchArm);
+    let field_arms = query_fields.iter().map(QueryMatchArm);
+    let checks = unique_fields
+        .iter()
+        .map(|field| unique_check(field, true))
+        .chain(query_fields.iter().map(|field| unique_check(field, false)));
+    let schema = Ident::new(&format!("{ident}Schema"), ident.span());
+    let out = quote! {
+        /// The [`datacache::StorageSchema`] generated by `storage!`.
+        #vis struct #schema;
 
-        #[datacache::__internal::async_trait]
-        impl datacache::DataStorage<#executor_path, #data_path> for #ident {
-            async fn find_one(
-                &self,
-                query: &<#data_path as datacache::DataMarker>::Query,
-            ) -> Result<datacache::Data<#data_path>, std::sync::Arc<<#executor_path as datacache::DataQueryExecutor<#data_path>>::Error>> {
-                if let Some(id) = self.find_id(query) {
-                    if let Some(data) = self.data.get(&id) {
-                        return Ok(data);
-                    }
-                }
-                let fut = datacache::__internal::FutureExt::map(datacache::DataQueryExecutor::find_one(self.executor.as_ref(), query), |out| out.map(|v| Some(datacache::Data::new(v))));
-                let data = self.query_cache.try_get_with(query.clone(), fut).await?.expect("Option should be Some(...)");
-                self.insert_data(data.clone()).await;
-                Ok(data)
+        impl datacache::StorageSchema<#executor_path, #data_path> for #schema {
+            fn name() -> &'static str {
+                stringify!(#ident)
             }
-            async fn find_all(
-                &self,
-                query: Option<&<#data_path as datacache::DataMarker>::Query>,
-            ) -> Result<Vec<datacache::Data<#data_path>>, std::sync::Arc<<#executor_path as datacache::DataQueryExecutor<#data_path>>::Error>>
-            {
-                let ids = datacache::DataQueryExecutor::find_all_ids(self.executor.as_ref(), query).await?;
-                let mut values = Vec::new();
-                for id in ids {
-                    let data = self.find_one(&<#data_path as datacache::DataMarker>::Query::#id_field(id)).await?;
-                    values.push(data);
-                }
-                Ok(values)
+
+            fn query_id(query: &<#data_path as datacache::DataMarker>::Query) -> Option<<#executor_path as datacache::DataQueryExecutor<#data_path>>::Id> {
+                #query_id
             }
-            async fn find_optional(
-                &self,
-                query: &<#data_path as datacache::DataMarker>::Query,
-            ) -> Result<Option<datacache::Data<#data_path>>, std::sync::Arc<<#executor_path as datacache::DataQueryExecutor<#data_path>>::Error>>
-            {
-                enum InternalLoadError {
-                    Error(<#executor_path as datacache::DataQueryExecutor<#data_path>>::Error),
-                    NotFound,
-                }
-                if let Some(id) = self.find_id(query) {
-                    if let Some(data) = self.data.get(&id) {
-                        return Ok(Some(data));
-                    }
-                }
-                let fut = datacache::__internal::FutureExt::map(datacache::DataQueryExecutor::find_optional(self.executor.as_ref(), query), |out| {
-                    out.map(|opt| opt.map(datacache::Data::new))
-                });
-                let data = self.query_cache.try_get_with(query.clone(), fut).await?;
-                if let Some(data) = &data {
-                    self.insert_data(data.clone()).await;
-                }
-                Ok(data)
+
+            fn id_query(id: &<#executor_path as datacache::DataQueryExecutor<#data_path>>::Id) -> <#data_path as datacache::DataMarker>::Query {
+                #id_query
             }
 
-            async fn delete(
-                &self,
-                query: &<#data_path as datacache::DataMarker>::Query,
-            ) -> Result<(), <#executor_path as datacache::DataQueryExecutor<#data_path>>::Error> {
-                self.query.remove(query);
-                self.query_cache.invalidate(query).await;
-                let ids = datacache::DataQueryExecutor::delete(self.executor.as_ref(), query).await?;
-                for id in ids {
-                    self.data.invalidate(&id).await;
+            #[allow(unused_variables)]
+            fn is_unique_query(query: &<#data_path as datacache::DataMarker>::Query) -> bool {
+                type Query = <#data_path as datacache::DataMarker>::Query;
+                match query {
+                    #(#unique_arms)*
+                    _ => false,
                 }
-                Ok(())
             }
-            async fn invalidate(
-                &self,
-                query: &<#data_path as datacache::DataMarker>::Query,
-            ) -> Result<(), <#executor_path as datacache::DataQueryExecutor<#data_path>>::Error> {
-                self.query.remove(query);
-                self.query_cache.invalidate(query).await;
-                let ids = datacache::DataQueryExecutor::find_all_ids(self.executor.as_ref(), Some(query)).await?;
-                for id in ids {
-                    self.data.invalidate(&id).await;
+
+            #[allow(unused_variables)]
+            fn is_field_query(query: &<#data_path as datacache::DataMarker>::Query) -> bool {
+                type Query = <#data_path as datacache::DataMarker>::Query;
+                match query {
+                    #(#field_arms)*
+                    _ => false,
                 }
-                Ok(())
             }
 
-            fn get_executor(&self) -> &#executor_path {
-                &self.executor
+            fn default_config() -> datacache::StorageConfig<#data_path> {
+                datacache::StorageConfig::new()#(#config)*
             }
         }
+
+        // The derive knows which queries are unique as well, both have to agree
+        const _: () = {
+            type Query = <#data_path as datacache::DataMarker>::Query;
+            #(#checks)*
+        };
+
+        #vis type #ident = datacache::Storage<#executor_path, #data_path, #schema>;
     };
     Ok(out)
 }
+
+/// Fails to compile if the `is_unique` of the query disagrees with the clause it is listed in.
+fn unique_check(field: &StorageField, unique: bool) -> TokenStream {
+    let ident = &field.0 .0;
+    let flag = unique_const(ident);
+    let (check, message) = if unique {
+        (
+            quote!(Query::#flag),
+            format!("`{ident}` is listed in `unique(...)`, but is neither the id, a composite query nor `queryable(unique)`"),
+        )
+    } else {
+        (
+            quote!(!Query::#flag),
+            format!("`{ident}` is listed in `fields(...)`, but is a unique query"),
+        )
+    };
+    quote_spanned!(ident.span()=> assert!(#check, #message);)
+}
@@ -1,11 +1,35 @@
 use std::{
+    any::TypeId,
     borrow::Borrow,
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap, HashSet,
+    },
     fmt::{Debug, Display, Pointer},
-    hash::Hash,
+    future::Future,
+    hash::{BuildHasher, Hash, Hasher},
+    marker::PhantomData,
     ops::Deref,
-    sync::Arc,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
+use dashmap::DashMap;
+use futures_channel::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use futures_util::{
+    future::{self, BoxFuture, Either, FutureExt, Shared},
+    stream::BoxStream,
+    StreamExt,
+};
+use moka::sync::ConcurrentCacheExt;
+
 pub use derive::DataMarker;
 
 #[doc(hidden)]
@@ -13,7 +37,9 @@ pub mod __internal {
     pub use async_trait::async_trait;
     pub use dashmap;
     pub use derive::storage;
+    pub use futures_util::future::{join, ready, BoxFuture};
     pub use futures_util::FutureExt;
+    pub use futures_util::StreamExt;
     pub use moka;
     #[cfg(feature = "serde")]
     pub use serde::Deserialize;
@@ -23,252 +49,2655 @@ pub mod __internal {
 
 #[macro_export]
 macro_rules! storage {
-    ($vis:vis $ident:ident($exc:ty, $data:ty), id($id_field:ident: $id_ty:ty), unique($($unique:ident: $unique_ty:ty),* ), fields($($field:ident: $field_ty:ty),* )) => {
-        $crate::__internal::storage!($vis $ident($exc, $data), id($id_field: $id_ty), unique($($unique: $unique_ty),*), fields($($field: $field_ty),*));
+    ($vis:vis $ident:ident($exc:ty, $data:ty), $(id($id_field:ident: $id_ty:ty),)? unique($($unique:ident: $unique_ty:ty),* ), fields($($field:ident: $field_ty:ty),* ) $(, config($($config:tt)*))? $(,)?) => {
+        $crate::__internal::storage!($vis $ident($exc, $data), $(id($id_field: $id_ty),)? unique($($unique: $unique_ty),*), fields($($field: $field_ty),*) $(, config($($config)*))?);
     };
 }
 
+type Weigher<D> = Arc<dyn Fn(&D) -> u32 + Send + Sync>;
+
+/// How long misses are cached if neither a negative nor a regular time to live is set, a
+/// miss is never invalidated by writes of other processes without an invalidation bus.
+pub const DEFAULT_NEGATIVE_TIME_TO_LIVE: Duration = Duration::from_secs(60);
+
+/// Sleeps for the given duration on the runtime of the storage, e.g. `tokio::time::sleep`.
+pub type Sleep = Arc<dyn Fn(Duration) -> BoxFuture<'static, ()> + Send + Sync>;
+
+fn boxed_sleep<F>(sleep: impl Fn(Duration) -> F + Send + Sync + 'static) -> Sleep
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |duration| sleep(duration).boxed())
+}
+
+/// Runtime settings for the caches of a storage generated by [`storage!`].
+///
+/// The settings apply to both the data cache and the query cache. Everything is
+/// unbounded and never expires unless configured otherwise.
+pub struct StorageConfig<D> {
+    max_capacity: Option<u64>,
+    time_to_live: Option<Duration>,
+    time_to_idle: Option<Duration>,
+    weigher: Option<Weigher<D>>,
+    batch_window: Option<(Duration, Sleep)>,
+    negative_cache: bool,
+    negative_time_to_live: Option<Duration>,
+    timeout: Option<(Duration, Sleep)>,
+    backend: BackendKind,
+}
+
+impl<D> StorageConfig<D> {
+    pub fn new() -> Self {
+        Self {
+            max_capacity: None,
+            time_to_live: None,
+            time_to_idle: None,
+            weigher: None,
+            batch_window: None,
+            negative_cache: true,
+            negative_time_to_live: None,
+            timeout: None,
+            backend: BackendKind::Moka,
+        }
+    }
+
+    /// Maximum number of entries, or the maximum total weight if a weigher is set.
+    pub fn max_capacity(mut self, max_capacity: u64) -> Self {
+        self.max_capacity = Some(max_capacity);
+        self
+    }
+
+    /// Also bounds how long the ids of a field query are kept without asking the executor.
+    pub fn time_to_live(mut self, duration: Duration) -> Self {
+        self.time_to_live = Some(duration);
+        self
+    }
+
+    pub fn time_to_idle(mut self, duration: Duration) -> Self {
+        self.time_to_idle = Some(duration);
+        self
+    }
+
+    pub fn weigher(mut self, weigher: impl Fn(&D) -> u32 + Send + Sync + 'static) -> Self {
+        self.weigher = Some(Arc::new(weigher));
+        self
+    }
+
+    /// Collects id lookups which miss the cache for the given duration and loads them
+    /// with a single [`DataQueryExecutor::find_many`] call. The storage does not depend on a
+    /// runtime, so `sleep` provides the timer, e.g. `tokio::time::sleep`.
+    pub fn batch_window<F>(
+        mut self,
+        duration: Duration,
+        sleep: impl Fn(Duration) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.batch_window = Some((duration, boxed_sleep(sleep)));
+        self
+    }
+
+    /// Enables or disables caching of queries which did not match any entity.
+    pub fn negative_cache(mut self, enabled: bool) -> Self {
+        self.negative_cache = enabled;
+        self
+    }
+
+    /// Time to live of cached misses, defaults to the time to live of the other caches or
+    /// [`DEFAULT_NEGATIVE_TIME_TO_LIVE`] without one.
+    pub fn negative_time_to_live(mut self, duration: Duration) -> Self {
+        self.negative_time_to_live = Some(duration);
+        self
+    }
+
+    /// Fails executor calls which take longer than the given duration with [`Error::Timeout`],
+    /// measured with the `sleep` timer like [`StorageConfig::batch_window`].
+    pub fn timeout<F>(
+        mut self,
+        duration: Duration,
+        sleep: impl Fn(Duration) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.timeout = Some((duration, boxed_sleep(sleep)));
+        self
+    }
+
+    /// Selects the [`CacheBackend`] the storage keeps entities, query results and misses in.
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn get_max_capacity(&self) -> Option<u64> {
+        self.max_capacity
+    }
+
+    pub fn get_time_to_live(&self) -> Option<Duration> {
+        self.time_to_live
+    }
+
+    pub fn get_time_to_idle(&self) -> Option<Duration> {
+        self.time_to_idle
+    }
+
+    pub fn get_batch_window(&self) -> Option<Duration> {
+        self.batch_window.as_ref().map(|(duration, _)| *duration)
+    }
+
+    pub fn get_negative_cache(&self) -> bool {
+        self.negative_cache
+    }
+
+    pub fn get_negative_time_to_live(&self) -> Duration {
+        self.negative_time_to_live
+            .or(self.time_to_live)
+            .unwrap_or(DEFAULT_NEGATIVE_TIME_TO_LIVE)
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout.as_ref().map(|(duration, _)| *duration)
+    }
+
+    pub fn get_backend(&self) -> BackendKind {
+        self.backend
+    }
+}
+
+impl<D: Send + Sync + 'static> StorageConfig<D> {
+    fn cache_builder<K, V>(
+        &self,
+        weigh: impl Fn(&V) -> Option<&D> + Send + Sync + 'static,
+    ) -> moka::sync::CacheBuilder<K, V, moka::sync::Cache<K, V>>
+    where
+        K: Hash + Eq + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+    {
+        let mut builder = moka::sync::Cache::builder();
+        if let Some(max_capacity) = self.max_capacity {
+            builder = builder.max_capacity(max_capacity);
+        }
+        if let Some(duration) = self.time_to_live {
+            builder = builder.time_to_live(duration);
+        }
+        if let Some(duration) = self.time_to_idle {
+            builder = builder.time_to_idle(duration);
+        }
+        if let Some(weigher) = self.weigher.clone() {
+            builder = builder.weigher(move |_, value| weigh(value).map_or(1, |data| weigher(data)));
+        }
+        builder
+    }
+
+    fn build_data_cache<K>(&self) -> Arc<dyn CacheBackend<K, Data<D>>>
+    where
+        K: Clone + Hash + Eq + Send + Sync + 'static,
+    {
+        match self.backend {
+            BackendKind::Moka => Arc::new(MokaBackend::new(
+                self.cache_builder(|data: &Data<D>| Some(data.as_ref())),
+            )),
+            BackendKind::HashMap => Arc::new(HashMapBackend::new()),
+            BackendKind::Noop => Arc::new(NoopBackend),
+        }
+    }
+
+    fn build_negative_cache<K>(&self) -> Option<Arc<dyn CacheBackend<K, ()>>>
+    where
+        K: Clone + Hash + Eq + Send + Sync + 'static,
+    {
+        if !self.negative_cache {
+            return None;
+        }
+        Some(match self.backend {
+            BackendKind::Moka => {
+                let mut builder = moka::sync::Cache::builder();
+                if let Some(max_capacity) = self.max_capacity {
+                    builder = builder.max_capacity(max_capacity);
+                }
+                builder = builder.time_to_live(self.get_negative_time_to_live());
+                if let Some(duration) = self.time_to_idle {
+                    builder = builder.time_to_idle(duration);
+                }
+                Arc::new(MokaBackend::new(builder))
+            }
+            BackendKind::HashMap => Arc::new(HashMapBackend::with_time_to_live(
+                self.get_negative_time_to_live(),
+            )),
+            BackendKind::Noop => Arc::new(NoopBackend),
+        })
+    }
+}
+
+impl<D> Default for StorageConfig<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Clone for StorageConfig<D> {
+    fn clone(&self) -> Self {
+        Self {
+            max_capacity: self.max_capacity,
+            time_to_live: self.time_to_live,
+            time_to_idle: self.time_to_idle,
+            weigher: self.weigher.clone(),
+            batch_window: self.batch_window.clone(),
+            negative_cache: self.negative_cache,
+            negative_time_to_live: self.negative_time_to_live,
+            timeout: self.timeout.clone(),
+            backend: self.backend,
+        }
+    }
+}
+
+impl<D> Debug for StorageConfig<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageConfig")
+            .field("max_capacity", &self.max_capacity)
+            .field("time_to_live", &self.time_to_live)
+            .field("time_to_idle", &self.time_to_idle)
+            .field("weigher", &self.weigher.is_some())
+            .field("batch_window", &self.get_batch_window())
+            .field("negative_cache", &self.negative_cache)
+            .field("negative_time_to_live", &self.negative_time_to_live)
+            .field("timeout", &self.get_timeout())
+            .field("backend", &self.backend)
+            .finish()
+    }
+}
+
+/// The built-in [`CacheBackend`]s a [`StorageConfig`] can select.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    /// [`MokaBackend`], which honors capacity, weigher and expiry settings.
+    #[default]
+    Moka,
+    /// [`HashMapBackend`], which ignores capacity and expiry settings and never evicts
+    /// entities. Misses still expire after the negative time to live.
+    HashMap,
+    /// [`NoopBackend`], every lookup goes to the executor.
+    Noop,
+}
+
+/// Where a storage keeps cached values. A storage has one backend each for entities by id,
+/// query results and misses.
+pub trait CacheBackend<K, V>: Send + Sync {
+    fn get(&self, key: &K) -> Option<V>;
+
+    fn insert(&self, key: K, value: V);
+
+    fn invalidate(&self, key: &K);
+
+    /// Iterates over the cached entries, the iteration might not see concurrent changes.
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn entry_count(&self) -> u64 {
+        self.iter().count() as u64
+    }
+
+    /// Runs pending maintenance tasks, if the backend has any.
+    fn sync(&self) {}
+
+    /// Registers the listener a storage uses to drop the indexes of evicted entities.
+    ///
+    /// Backends which drop entries on their own, e.g. because of capacity or expiry, have to
+    /// call it for every such entry before the next lookup. Replacements and invalidations
+    /// are not evictions. Backends which never evict can ignore it.
+    fn set_eviction_listener(&self, _listener: EvictionListener<K, V>) {}
+}
+
+/// Called by a [`CacheBackend`] for every entry it evicted.
+pub type EvictionListener<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+
+/// A [`CacheBackend`] on top of a [`moka::sync::Cache`], the default.
+#[derive(Clone)]
+pub struct MokaBackend<K, V> {
+    cache: moka::sync::Cache<K, V>,
+    listener: Arc<RwLock<Option<EvictionListener<K, V>>>>,
+}
+
+impl<K, V> MokaBackend<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Builds the cache, the builder must not have an eviction listener of its own.
+    pub fn new(builder: moka::sync::CacheBuilder<K, V, moka::sync::Cache<K, V>>) -> Self {
+        let listener = Arc::new(RwLock::new(None::<EvictionListener<K, V>>));
+        let cache = builder
+            .eviction_listener({
+                let listener = Arc::clone(&listener);
+                move |key, value, cause| {
+                    if !cause.was_evicted() {
+                        return;
+                    }
+                    let listener = listener.read().ok().and_then(|listener| listener.clone());
+                    if let Some(listener) = listener {
+                        listener(&key, &value);
+                    }
+                }
+            })
+            .build();
+        Self { cache, listener }
+    }
+}
+
+impl<K, V> CacheBackend<K, V> for MokaBackend<K, V>
+where
+    K: Clone + Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.cache.get(key)
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.cache.insert(key, value);
+    }
+
+    fn invalidate(&self, key: &K) {
+        self.cache.invalidate(key);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(
+            self.cache
+                .iter()
+                .map(|(key, value)| (K::clone(&key), value)),
+        )
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.cache.contains_key(key)
+    }
+
+    fn entry_count(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    fn sync(&self) {
+        self.cache.sync();
+    }
+
+    fn set_eviction_listener(&self, listener: EvictionListener<K, V>) {
+        if let Ok(mut slot) = self.listener.write() {
+            *slot = Some(listener);
+        }
+    }
+}
+
+/// An unbounded [`CacheBackend`] without background maintenance, entries stay until they are
+/// invalidated or, with a time to live, until they are read or synced after it passed.
+pub struct HashMapBackend<K, V> {
+    entries: RwLock<HashMap<K, (V, Option<Instant>)>>,
+    time_to_live: Option<Duration>,
+    listener: RwLock<Option<EvictionListener<K, V>>>,
+}
+
+impl<K, V> HashMapBackend<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            time_to_live: None,
+            listener: RwLock::new(None),
+        }
+    }
+
+    /// Expires entries the given duration after they were inserted.
+    pub fn with_time_to_live(duration: Duration) -> Self {
+        Self {
+            time_to_live: Some(duration),
+            ..Self::new()
+        }
+    }
+}
+
+impl<K, V> HashMapBackend<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    fn is_expired(expires: &Option<Instant>) -> bool {
+        expires.is_some_and(|expires| expires <= Instant::now())
+    }
+
+    /// Removes the expired entries and reports them as evicted.
+    fn expire(&self, filter: impl Fn(&K) -> bool) {
+        let expired: Vec<_> = match self.entries.write() {
+            Ok(mut entries) => {
+                let expired: Vec<_> = entries
+                    .iter()
+                    .filter(|(key, (_, expires))| filter(key) && Self::is_expired(expires))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                expired
+                    .into_iter()
+                    .filter_map(|key| entries.remove_entry(&key))
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        };
+        let listener = self
+            .listener
+            .read()
+            .ok()
+            .and_then(|listener| listener.clone());
+        if let Some(listener) = listener {
+            for (key, (value, _)) in expired {
+                listener(&key, &value);
+            }
+        }
+    }
+}
+
+impl<K, V> Default for HashMapBackend<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> CacheBackend<K, V> for HashMapBackend<K, V>
+where
+    K: Clone + Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        {
+            let entries = self.entries.read().ok()?;
+            let (value, expires) = entries.get(key)?;
+            if !Self::is_expired(expires) {
+                return Some(value.clone());
+            }
+        }
+        self.expire(|other| other == key);
+        None
+    }
+
+    fn insert(&self, key: K, value: V) {
+        let expires = self.time_to_live.map(|duration| Instant::now() + duration);
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(key, (value, expires));
+        }
+    }
+
+    fn invalidate(&self, key: &K) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.remove(key);
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        let entries: Vec<_> = match self.entries.read() {
+            Ok(entries) => entries
+                .iter()
+                .filter(|(_, (_, expires))| !Self::is_expired(expires))
+                .map(|(key, (value, _))| (key.clone(), value.clone()))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        Box::new(entries.into_iter())
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn entry_count(&self) -> u64 {
+        self.entries.read().map_or(0, |entries| {
+            entries
+                .values()
+                .filter(|(_, expires)| !Self::is_expired(expires))
+                .count() as u64
+        })
+    }
+
+    fn sync(&self) {
+        self.expire(|_| true);
+    }
+
+    fn set_eviction_listener(&self, listener: EvictionListener<K, V>) {
+        if let Ok(mut slot) = self.listener.write() {
+            *slot = Some(listener);
+        }
+    }
+}
+
+impl<K, V, B: CacheBackend<K, V> + ?Sized> CacheBackend<K, V> for Arc<B> {
+    fn get(&self, key: &K) -> Option<V> {
+        B::get(self, key)
+    }
+
+    fn insert(&self, key: K, value: V) {
+        B::insert(self, key, value)
+    }
+
+    fn invalidate(&self, key: &K) {
+        B::invalidate(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        B::iter(self)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        B::contains_key(self, key)
+    }
+
+    fn entry_count(&self) -> u64 {
+        B::entry_count(self)
+    }
+
+    fn sync(&self) {
+        B::sync(self)
+    }
+
+    fn set_eviction_listener(&self, listener: EvictionListener<K, V>) {
+        B::set_eviction_listener(self, listener)
+    }
+}
+
+/// A [`CacheBackend`] which keeps nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopBackend;
+
+impl<K: 'static, V: 'static> CacheBackend<K, V> for NoopBackend {
+    fn get(&self, _key: &K) -> Option<V> {
+        None
+    }
+
+    fn insert(&self, _key: K, _value: V) {}
+
+    fn invalidate(&self, _key: &K) {}
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(std::iter::empty())
+    }
+}
+
+type BatchResult<K, V, E> = Result<Arc<HashMap<K, V>>, Error<E>>;
+type BatchFn<K, V, E> =
+    dyn Fn(Vec<K>) -> BoxFuture<'static, Result<Vec<(K, V)>, Error<E>>> + Send + Sync;
+
+struct PendingBatch<K, V, E> {
+    keys: HashSet<K>,
+    future: Shared<BoxFuture<'static, BatchResult<K, V, E>>>,
+}
+
+/// Coalesces concurrent loads of single keys into batches.
+///
+/// The first key opens a batch which stays open for the configured window. Every key
+/// requested in the meantime joins it and the whole batch is loaded by a single call.
+pub struct BatchLoader<K, V, E> {
+    window: Duration,
+    sleep: Sleep,
+    load: Arc<BatchFn<K, V, E>>,
+    pending: Arc<Mutex<Option<PendingBatch<K, V, E>>>>,
+}
+
+impl<K, V, E> BatchLoader<K, V, E>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    pub fn new(
+        window: Duration,
+        sleep: Sleep,
+        load: impl Fn(Vec<K>) -> BoxFuture<'static, Result<Vec<(K, V)>, Error<E>>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            window,
+            sleep,
+            load: Arc::new(load),
+            pending: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub async fn load(&self, key: K) -> Result<Option<V>, Error<E>> {
+        let future = {
+            let mut pending = self.pending.lock().map_err(|_| Error::Poisoned)?;
+            match pending.as_mut() {
+                Some(batch) => {
+                    batch.keys.insert(key.clone());
+                    batch.future.clone()
+                }
+                None => {
+                    let future = self.dispatch();
+                    *pending = Some(PendingBatch {
+                        keys: HashSet::from([key.clone()]),
+                        future: future.clone(),
+                    });
+                    future
+                }
+            }
+        };
+        future.await.map(|values| values.get(&key).cloned())
+    }
+
+    fn dispatch(&self) -> Shared<BoxFuture<'static, BatchResult<K, V, E>>> {
+        let sleep = (self.sleep)(self.window);
+        let load = Arc::clone(&self.load);
+        let pending = Arc::clone(&self.pending);
+        async move {
+            sleep.await;
+            // Close the batch, later keys open a new one
+            let keys = pending
+                .lock()
+                .map_err(|_| Error::Poisoned)?
+                .take()
+                .map(|batch| batch.keys.into_iter().collect())
+                .unwrap_or_default();
+            let values = load(keys).await?;
+            Ok(Arc::new(values.into_iter().collect()))
+        }
+        .boxed()
+        .shared()
+    }
+}
+
 pub trait DataMarker {
     type Query: Send + Sync + Hash + Eq + Debug;
 
     fn create_queries(&self) -> Vec<Self::Query>;
+
+    /// The entities this entity points to through its reference fields.
+    fn references(&self) -> Vec<DataReference> {
+        Vec::new()
+    }
+
+    /// The types of all entities which can be returned by [`DataMarker::references`].
+    fn referenced_types() -> Vec<TypeId>
+    where
+        Self: Sized,
+    {
+        Vec::new()
+    }
+
+    /// Names the kind of the query without its values, for tracing.
+    fn query_name(_query: &Self::Query) -> &'static str
+    where
+        Self: Sized,
+    {
+        std::any::type_name::<Self::Query>()
+    }
 }
 
-#[repr(transparent)]
-pub struct Data<T>(Arc<T>);
+/// Implemented by `#[derive(DataMarker)]` for entities with a `#[datacache(id)]` field.
+/// [`IdSchema`], and through it [`storage!`] without an `id(...)` clause, map between ids and
+/// queries with it.
+pub trait DataId: DataMarker {
+    type Id: Send + Sync + Hash + Eq + Clone;
+
+    fn id(&self) -> Self::Id;
+    fn id_query(id: Self::Id) -> Self::Query;
+    /// The id if the query is an id query.
+    fn query_id(query: &Self::Query) -> Option<Self::Id>;
+}
+
+/// A type erased [`DataRef`], identifying the referenced entity by its type and a hash of
+/// the query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DataReference {
+    data: TypeId,
+    query: u64,
+}
+
+impl DataReference {
+    pub fn new<D: DataMarker + 'static>(query: &D::Query) -> Self {
+        let mut hasher = DefaultHasher::new();
+        query.hash(&mut hasher);
+        Self {
+            data: TypeId::of::<D>(),
+            query: hasher.finish(),
+        }
+    }
+
+    pub fn data_type(&self) -> TypeId {
+        self.data
+    }
+}
+
+impl<D: DataMarker + 'static> From<&DataRef<D>> for DataReference {
+    fn from(reference: &DataRef<D>) -> Self {
+        Self::new::<D>(&reference.0)
+    }
+}
+
+/// Field types which are tracked as references.
+///
+/// The derive picks up fields of type `DataRef<_>`, `Option<DataRef<_>>` and
+/// `Vec<DataRef<_>>` by their name. Other implementations, including aliases of those types,
+/// have to be marked with `#[datacache(references)]`.
+pub trait References {
+    fn collect_references(&self, references: &mut Vec<DataReference>);
+    fn referenced_types(types: &mut Vec<TypeId>);
+}
+
+impl<D: DataMarker + 'static> References for DataRef<D> {
+    fn collect_references(&self, references: &mut Vec<DataReference>) {
+        references.push(self.into())
+    }
+
+    fn referenced_types(types: &mut Vec<TypeId>) {
+        types.push(TypeId::of::<D>())
+    }
+}
+
+impl<T: References> References for Option<T> {
+    fn collect_references(&self, references: &mut Vec<DataReference>) {
+        if let Some(value) = self {
+            value.collect_references(references)
+        }
+    }
+
+    fn referenced_types(types: &mut Vec<TypeId>) {
+        T::referenced_types(types)
+    }
+}
+
+impl<T: References> References for Vec<T> {
+    fn collect_references(&self, references: &mut Vec<DataReference>) {
+        for value in self {
+            value.collect_references(references)
+        }
+    }
+
+    fn referenced_types(types: &mut Vec<TypeId>) {
+        T::referenced_types(types)
+    }
+}
+
+#[repr(transparent)]
+pub struct Data<T>(Arc<T>);
+
+impl<T: DataMarker> Data<T> {
+    pub fn new(data: T) -> Self {
+        Self(Arc::new(data))
+    }
+}
+
+impl<T: DataMarker> DataMarker for Data<T> {
+    type Query = T::Query;
+
+    fn create_queries(&self) -> Vec<Self::Query> {
+        T::create_queries(&self.0)
+    }
+
+    fn references(&self) -> Vec<DataReference> {
+        T::references(&self.0)
+    }
+
+    fn referenced_types() -> Vec<TypeId> {
+        T::referenced_types()
+    }
+
+    fn query_name(query: &Self::Query) -> &'static str {
+        T::query_name(query)
+    }
+}
+
+impl<T> Deref for Data<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.deref()
+    }
+}
+
+impl<T> AsRef<T> for Data<T> {
+    fn as_ref(&self) -> &T {
+        self.0.as_ref()
+    }
+}
+
+impl<T> Borrow<T> for Data<T> {
+    fn borrow(&self) -> &T {
+        self.0.borrow()
+    }
+}
+
+impl<T> Clone for Data<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: Debug> Debug for Data<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+impl<T: Display> Display for Data<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+impl<T: Hash> Hash for Data<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+impl<T: PartialEq> PartialEq<Data<T>> for Data<T> {
+    fn eq(&self, other: &Data<T>) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for Data<T> {
+    #[inline(always)]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        T::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de> + DataMarker> serde::Deserialize<'de> for Data<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(Data::new)
+    }
+}
+
+impl<T: Eq> Eq for Data<T> {}
+
+impl<T: PartialOrd> PartialOrd<Data<T>> for Data<T> {
+    fn partial_cmp(&self, other: &Data<T>) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(&other.0)
+    }
+}
+
+impl<T: Ord> Ord for Data<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+impl<T: Pointer> Pointer for Data<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[macro_export]
+macro_rules! storage_ref {
+    ($vis:vis $ident:ident) => {
+        $vis trait $ident: datacache::DataMarker + Sized {
+            type Exc: datacache::DataQueryExecutor<Self>;
+            type Storage: datacache::DataStorage<Self::Exc, Self> + Clone;
+        }
+    };
+    ($data:path: $ref:ident where Exc: $exc:path, Storage: $storage:path) => {
+        impl $ref for $data {
+            type Exc = $exc;
+            type Storage = $storage;
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(transparent)]
+pub struct DataRef<D: DataMarker>(pub D::Query);
+
+impl<D> Debug for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DataRef").field(&self.0).finish()
+    }
+}
+impl<D> Display for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl<D> Clone for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Clone,
+{
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<D> PartialEq for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: PartialEq,
+{
+    #[inline(always)]
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+impl<D> Eq for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Clone + Eq,
+{
+}
+impl<D> PartialOrd for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Clone + PartialOrd,
+{
+    #[inline(always)]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(&other.0)
+    }
+}
+impl<D> Ord for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Clone + Ord,
+{
+    #[inline(always)]
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+impl<D> Hash for DataRef<D>
+where
+    D: DataMarker,
+    D::Query: Hash,
+{
+    #[inline(always)]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<D: DataMarker> DataRef<D> {
+    pub fn new(query: D::Query) -> Self {
+        Self(query)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait DataQueryExecutor<D: DataMarker>: Sized + Send + Sync {
+    type Error: Display;
+    type Id: Send + Sync + Hash + Eq + Clone;
+
+    fn get_id(&self, data: &D) -> Self::Id;
+    async fn find_one(&self, query: &D::Query) -> Result<D, Self::Error>;
+    /// Loads all entities for the given ids in one go. Ids which do not exist are skipped
+    /// and the order of the returned entities does not matter.
+    ///
+    /// Returns `None` if the executor cannot load several entities at once, the storage
+    /// then falls back to one `find_optional` call per id, which is the default.
+    async fn find_many(&self, _ids: &[Self::Id]) -> Result<Option<Vec<D>>, Self::Error> {
+        Ok(None)
+    }
+    // async fn find_all(&self, query: D::Query) -> Result<Vec<D>, Self::Error>;
+    async fn find_all_ids(&self, query: Option<&D::Query>) -> Result<Vec<Self::Id>, Self::Error>;
+    async fn find_optional(&self, query: &D::Query) -> Result<Option<D>, Self::Error>;
+    async fn delete(&self, data: &D::Query) -> Result<Vec<Self::Id>, Self::Error>;
+}
+
+/// Executors which can also store entities, required by [`DataWriteStorage`].
+#[async_trait::async_trait]
+pub trait DataWriteExecutor<D: DataMarker>: DataQueryExecutor<D> {
+    async fn insert(&self, data: D) -> Result<D, Self::Error>;
+    async fn update(&self, data: D) -> Result<D, Self::Error>;
+    async fn upsert(&self, data: D) -> Result<D, Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The query did not match any entity.
+    NotFound,
+    /// The executor failed, the error is shared between all callers waiting for the same load.
+    Executor(Arc<E>),
+    /// A lock of the storage was poisoned by a panicking thread.
+    Poisoned,
+    /// The executor did not answer within the configured timeout.
+    Timeout,
+}
+
+impl<E> Error<E> {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound)
+    }
+
+    pub fn executor_error(&self) -> Option<&E> {
+        match self {
+            Self::Executor(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl<E> Clone for Error<E> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::NotFound => Self::NotFound,
+            Self::Executor(err) => Self::Executor(Arc::clone(err)),
+            Self::Poisoned => Self::Poisoned,
+            Self::Timeout => Self::Timeout,
+        }
+    }
+}
+
+impl<E: Display> Display for Error<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("not found"),
+            Self::Executor(err) => Display::fmt(err, f),
+            Self::Poisoned => f.write_str("lock poisoned"),
+            Self::Timeout => f.write_str("executor timed out"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for Error<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Executor(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A snapshot of the counters and sizes of a storage generated by [`storage!`].
+///
+/// Entry counts of the caches are approximate until pending maintenance tasks ran.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// Lookups answered from the caches, including cached misses.
+    pub hits: u64,
+    /// Lookups which had to ask the executor.
+    pub misses: u64,
+    pub executor_calls: u64,
+    /// Failed executor calls, including timeouts.
+    pub errors: u64,
+    /// Entities removed from the data cache because of their capacity or expiry.
+    pub evictions: u64,
+    pub data_entries: u64,
+    pub query_cache_entries: u64,
+    pub negative_entries: u64,
+    /// Entries of the unique query index.
+    pub index_entries: usize,
+    /// Entries of the non-unique field index.
+    pub field_index_entries: usize,
+}
+
+#[doc(hidden)]
+pub struct StorageCounters {
+    name: &'static str,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    executor_calls: AtomicU64,
+    errors: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl StorageCounters {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            executor_calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn increment(&self, counter: &AtomicU64, _metric: &'static str, value: u64) {
+        if value == 0 {
+            return;
+        }
+        counter.fetch_add(value, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!(_metric, "storage" => self.name).increment(value);
+    }
+
+    pub fn hit(&self) {
+        self.lookups(1, 0)
+    }
+
+    pub fn miss(&self) {
+        self.lookups(0, 1)
+    }
+
+    /// Counts the hits and misses of one lookup, which records a single cache outcome.
+    pub fn lookups(&self, hits: u64, misses: u64) {
+        self.increment(&self.hits, "datacache_hits_total", hits);
+        self.increment(&self.misses, "datacache_misses_total", misses);
+        #[cfg(feature = "tracing")]
+        {
+            let outcome = match (hits, misses) {
+                (0, 0) => return,
+                (_, 0) => "hit",
+                (0, _) => "miss",
+                _ => "partial",
+            };
+            tracing::Span::current().record("cache", outcome);
+        }
+    }
+
+    /// Runs a storage method inside a span carrying the storage name, the method and the
+    /// name of the query. Without the `tracing` feature the future is awaited as is.
+    pub async fn instrument<T>(
+        &self,
+        _method: &'static str,
+        _query: Option<&'static str>,
+        fut: impl Future<Output = T>,
+    ) -> T {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::debug_span!(
+                "datacache",
+                storage = self.name,
+                method = _method,
+                query = _query,
+                cache = tracing::field::Empty,
+                executor_latency_us = tracing::field::Empty,
+            );
+            tracing::Instrument::instrument(fut, span).await
+        }
+        #[cfg(not(feature = "tracing"))]
+        fut.await
+    }
+
+    /// Like [`StorageCounters::instrument`] for a batch load, which gets a span of its own
+    /// instead of one below whichever caller happens to drive the shared load.
+    pub async fn instrument_batch<T>(&self, _keys: usize, fut: impl Future<Output = T>) -> T {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::debug_span!(
+                parent: None,
+                "datacache",
+                storage = self.name,
+                method = "batch_load",
+                keys = _keys,
+                executor_latency_us = tracing::field::Empty,
+            );
+            tracing::Instrument::instrument(fut, span).await
+        }
+        #[cfg(not(feature = "tracing"))]
+        fut.await
+    }
+
+    pub fn eviction(&self) {
+        self.increment(&self.evictions, "datacache_evictions_total", 1)
+    }
+
+    /// Runs an executor call, failing it with [`Error::Timeout`] once the timeout slept.
+    pub async fn execute<T, E>(
+        &self,
+        timeout: Option<&(Duration, Sleep)>,
+        fut: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, Error<E>> {
+        self.increment(&self.executor_calls, "datacache_executor_calls_total", 1);
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let fut = pin!(fut);
+        let res = match timeout {
+            Some((timeout, sleep)) => match future::select(fut, sleep(*timeout)).await {
+                Either::Left((res, _)) => res.map_err(|err| Error::Executor(Arc::new(err))),
+                Either::Right(_) => Err(Error::Timeout),
+            },
+            None => fut.await.map_err(|err| Error::Executor(Arc::new(err))),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("executor_latency_us", start.elapsed().as_micros() as u64);
+        if res.is_err() {
+            self.increment(&self.errors, "datacache_errors_total", 1);
+        }
+        res
+    }
+
+    pub fn stats(
+        &self,
+        data_entries: u64,
+        query_cache_entries: u64,
+        negative_entries: u64,
+        index_entries: usize,
+        field_index_entries: usize,
+    ) -> StorageStats {
+        #[cfg(feature = "metrics")]
+        {
+            let gauges = [
+                ("data", data_entries as f64),
+                ("query_cache", query_cache_entries as f64),
+                ("negative", negative_entries as f64),
+                ("index", index_entries as f64),
+                ("field_index", field_index_entries as f64),
+            ];
+            for (cache, value) in gauges {
+                metrics::gauge!("datacache_entries", "storage" => self.name, "cache" => cache)
+                    .set(value);
+            }
+        }
+        StorageStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            executor_calls: self.executor_calls.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            data_entries,
+            query_cache_entries,
+            negative_entries,
+            index_entries,
+            field_index_entries,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait DataStorage<Exc: DataQueryExecutor<D>, D: DataMarker>: Send + Sync {
+    async fn find_one(&self, query: &D::Query) -> Result<Data<D>, Error<Exc::Error>>;
+    /// Entities removed between listing their ids and loading them are skipped.
+    async fn find_all(&self, query: Option<&D::Query>) -> Result<Vec<Data<D>>, Error<Exc::Error>>;
+    async fn find_optional(&self, query: &D::Query) -> Result<Option<Data<D>>, Error<Exc::Error>>;
+
+    async fn delete(&self, query: &D::Query) -> Result<(), Error<Exc::Error>>;
+    async fn invalidate(&self, query: &D::Query) -> Result<(), Error<Exc::Error>>;
+
+    /// Drops all cached entities which reference the given entity and returns references to
+    /// the dropped entities, so the invalidation can cascade further.
+    async fn invalidate_references(&self, _reference: &DataReference) -> Vec<DataReference> {
+        Vec::new()
+    }
+
+    /// All queries of the entities matching the query, so an invalidation cascades to the
+    /// entities which reference them by another query. Storages which cannot tell return
+    /// nothing and the cascade only follows the query itself.
+    async fn entity_queries(&self, _query: &D::Query) -> Result<Vec<D::Query>, Error<Exc::Error>> {
+        Ok(Vec::new())
+    }
+
+    fn get_executor(&self) -> &Exc;
+}
+
+/// Write-through operations, the caches are updated with the entities returned by the
+/// executor instead of being invalidated.
+#[async_trait::async_trait]
+pub trait DataWriteStorage<Exc: DataWriteExecutor<D>, D: DataMarker>: DataStorage<Exc, D> {
+    async fn insert(&self, data: D) -> Result<Data<D>, Error<Exc::Error>>;
+    async fn update(&self, data: D) -> Result<Data<D>, Error<Exc::Error>>;
+    async fn upsert(&self, data: D) -> Result<Data<D>, Error<Exc::Error>>;
+}
+
+/// Queries which were changed by one storage instance and have to be dropped from the
+/// caches of all other instances.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalidation<Q> {
+    /// Identifies the publishing storage instance, which skips its own events.
+    pub origin: u64,
+    pub queries: Vec<Q>,
+}
+
+#[doc(hidden)]
+pub fn new_origin() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    std::time::SystemTime::now().hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Distributes [`Invalidation`]s between the storage instances of several processes.
+#[async_trait::async_trait]
+pub trait InvalidationBus<Q>: Send + Sync {
+    async fn publish(&self, event: Invalidation<Q>);
+    fn subscribe(&self) -> BoxStream<'static, Invalidation<Q>>;
+}
+
+/// Delivers every published event to all subscribers of the same bus, for tests and
+/// for several storage instances within one process.
+pub struct LoopbackBus<Q> {
+    subscribers: Mutex<Vec<UnboundedSender<Invalidation<Q>>>>,
+}
+
+impl<Q: Clone> LoopbackBus<Q> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn deliver(&self, event: Invalidation<Q>) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+        }
+    }
+
+    fn add_subscriber(&self) -> UnboundedReceiver<Invalidation<Q>> {
+        let (sender, receiver) = mpsc::unbounded();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+}
+
+impl<Q: Clone> Default for LoopbackBus<Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl<Q: Clone + Send + 'static> InvalidationBus<Q> for LoopbackBus<Q> {
+    async fn publish(&self, event: Invalidation<Q>) {
+        self.deliver(event)
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, Invalidation<Q>> {
+        self.add_subscriber().boxed()
+    }
+}
+
+/// A bus backed by channels, to be connected to a remote transport such as Redis pub/sub
+/// or Postgres `LISTEN`/`NOTIFY`.
+///
+/// Published events are handed to the receiver returned by [`ChannelBus::new`], which the
+/// adapter forwards to the transport. Events received from the transport are passed to
+/// [`ChannelBus::deliver`] and reach all local subscribers.
+pub struct ChannelBus<Q> {
+    outgoing: UnboundedSender<Invalidation<Q>>,
+    incoming: LoopbackBus<Q>,
+}
+
+impl<Q: Clone> ChannelBus<Q> {
+    pub fn new() -> (Self, UnboundedReceiver<Invalidation<Q>>) {
+        let (outgoing, receiver) = mpsc::unbounded();
+        let bus = Self {
+            outgoing,
+            incoming: LoopbackBus::new(),
+        };
+        (bus, receiver)
+    }
+
+    pub fn deliver(&self, event: Invalidation<Q>) {
+        self.incoming.deliver(event)
+    }
+}
+
+#[async_trait::async_trait]
+impl<Q: Clone + Send + 'static> InvalidationBus<Q> for ChannelBus<Q> {
+    async fn publish(&self, event: Invalidation<Q>) {
+        // The adapter has shut down, there is nobody left to notify
+        let _ = self.outgoing.unbounded_send(event);
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, Invalidation<Q>> {
+        self.incoming.add_subscriber().boxed()
+    }
+}
+
+/// A second cache level shared between processes, e.g. backed by Redis. A [`Storage`] asks it
+/// before its executor and stores the results of the executor in it.
+///
+/// Entries are serialized [`Data`] keyed by the storage name and the serialized query, the id
+/// of an entity is stored under its id query. Failures of the cache should be treated as
+/// misses, the storage falls back to the executor.
+#[cfg(feature = "serde")]
+#[async_trait::async_trait]
+pub trait L2Cache: Send + Sync {
+    async fn get(&self, key: &str) -> Option<Vec<u8>>;
+    /// Stores the entry for at most `ttl`, the time to live of the storage if it has one.
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>);
+    async fn delete(&self, key: &str);
+}
+
+/// An [`L2Cache`] within the process, for tests and for several storage instances sharing
+/// one cache.
+#[cfg(feature = "serde")]
+#[derive(Default)]
+pub struct MemoryL2Cache {
+    entries: Mutex<HashMap<String, L2Entry>>,
+}
+
+/// A value and when it expires.
+#[cfg(feature = "serde")]
+type L2Entry = (Vec<u8>, Option<Instant>);
+
+#[cfg(feature = "serde")]
+impl MemoryL2Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The keys of all entries which did not expire yet.
+    pub fn keys(&self) -> Vec<String> {
+        self.entries
+            .lock()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|(_, (_, expires))| !Self::is_expired(expires))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_expired(expires: &Option<Instant>) -> bool {
+        expires.is_some_and(|expires| expires <= Instant::now())
+    }
+}
+
+#[cfg(feature = "serde")]
+#[async_trait::async_trait]
+impl L2Cache for MemoryL2Cache {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().ok()?;
+        let (value, expires) = entries.get(key)?;
+        if Self::is_expired(expires) {
+            entries.remove(key);
+            return None;
+        }
+        Some(value.clone())
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            let expires = ttl.map(|ttl| Instant::now() + ttl);
+            entries.insert(key.to_string(), (value, expires));
+        }
+    }
+
+    async fn delete(&self, key: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(key);
+        }
+    }
+}
+
+/// An [`L2Cache`] together with the serialization of one storage. The serialization is
+/// captured as function pointers, so only [`Storage::with_l2_cache`] needs the serde bounds.
+#[cfg(feature = "serde")]
+struct L2Tier<D: DataMarker> {
+    cache: Arc<dyn L2Cache>,
+    /// Prefix of the keys, shared by all processes storing the entity
+    namespace: String,
+    time_to_live: Option<Duration>,
+    encode: fn(&Data<D>) -> serde_json::Result<Vec<u8>>,
+    decode: fn(&[u8]) -> serde_json::Result<Data<D>>,
+    encode_query: fn(&D::Query) -> serde_json::Result<String>,
+}
+
+#[cfg(feature = "serde")]
+impl<D: DataMarker> L2Tier<D> {
+    fn key(&self, query: &D::Query) -> Option<String> {
+        let query = (self.encode_query)(query).ok()?;
+        Some(format!("{}:{query}", self.namespace))
+    }
+
+    async fn get(&self, query: &D::Query) -> Option<Data<D>> {
+        let key = self.key(query)?;
+        let value = self.cache.get(&key).await?;
+        match (self.decode)(&value) {
+            Ok(data) => Some(data),
+            Err(_) => {
+                // Most likely written by an older version of the entity
+                self.cache.delete(&key).await;
+                None
+            }
+        }
+    }
+
+    async fn set(&self, query: &D::Query, data: &Data<D>) {
+        if let (Some(key), Ok(value)) = (self.key(query), (self.encode)(data)) {
+            self.cache.set(&key, value, self.time_to_live).await;
+        }
+    }
+
+    async fn delete(&self, queries: &[D::Query]) {
+        for key in queries.iter().filter_map(|query| self.key(query)) {
+            self.cache.delete(&key).await;
+        }
+    }
+}
+
+/// What [`storage!`] knows about an entity beyond [`DataMarker`]: which queries resolve to an
+/// id and which ones are indexed.
+pub trait StorageSchema<Exc: DataQueryExecutor<D>, D: DataMarker>: 'static {
+    /// Name used for stats, metrics and tracing.
+    fn name() -> &'static str;
+    /// The id if the query is an id query.
+    fn query_id(query: &D::Query) -> Option<Exc::Id>;
+    /// The query which looks up the entity by its id.
+    fn id_query(id: &Exc::Id) -> D::Query;
+    /// Unique queries are indexed and resolve to a single id without asking the executor.
+    fn is_unique_query(_query: &D::Query) -> bool {
+        false
+    }
+    /// Field queries cache the complete set of matching ids.
+    fn is_field_query(_query: &D::Query) -> bool {
+        false
+    }
+    fn default_config() -> StorageConfig<D> {
+        StorageConfig::new()
+    }
+}
+
+/// The schema for entities with a `#[datacache(id)]` field and no further indexes.
+pub struct IdSchema;
+
+impl<Exc, D> StorageSchema<Exc, D> for IdSchema
+where
+    Exc: DataQueryExecutor<D, Id = D::Id>,
+    D: DataId,
+{
+    fn name() -> &'static str {
+        std::any::type_name::<D>()
+    }
+
+    fn query_id(query: &D::Query) -> Option<Exc::Id> {
+        D::query_id(query)
+    }
+
+    fn id_query(id: &Exc::Id) -> D::Query {
+        D::id_query(id.clone())
+    }
+}
+
+type IdSet<Exc, D> = HashSet<<Exc as DataQueryExecutor<D>>::Id>;
+type FieldIndex<Exc, D> =
+    DashMap<<D as DataMarker>::Query, FieldIds<<Exc as DataQueryExecutor<D>>::Id>>;
+
+/// The ids of all entities with a field value, loaded again once the set expired.
+struct FieldIds<Id> {
+    ids: HashSet<Id>,
+    expires: Option<Instant>,
+}
+
+impl<Id> FieldIds<Id> {
+    fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= Instant::now())
+    }
+}
+type StorageLoader<Exc, D> =
+    BatchLoader<<Exc as DataQueryExecutor<D>>::Id, Data<D>, <Exc as DataQueryExecutor<D>>::Error>;
+type PendingQuery<Exc, D> = Shared<
+    BoxFuture<'static, Result<Option<Data<D>>, Error<<Exc as DataQueryExecutor<D>>::Error>>>,
+>;
+
+/// The [`DataStorage`] implementation, [`storage!`] only generates the [`StorageSchema`] and
+/// an alias for this type.
+pub struct Storage<Exc: DataQueryExecutor<D>, D: DataMarker, S = IdSchema> {
+    executor: Arc<Exc>,
+    data: Arc<dyn CacheBackend<Exc::Id, Data<D>>>,
+    query_cache: Arc<dyn CacheBackend<D::Query, Data<D>>>,
+    missing: Option<Arc<dyn CacheBackend<D::Query, ()>>>,
+    /// Executor calls of `find_optional` in flight, concurrent lookups of a query share them
+    pending: Arc<DashMap<D::Query, PendingQuery<Exc, D>>>,
+    /// Bumped by every write, lookups which saw a write while loading do not cache the result
+    writes: Arc<AtomicU64>,
+    query: Arc<DashMap<D::Query, Exc::Id>>,
+    fields: Arc<FieldIndex<Exc, D>>,
+    references: Arc<DashMap<DataReference, IdSet<Exc, D>>>,
+    batch: Option<Arc<StorageLoader<Exc, D>>>,
+    timeout: Option<(Duration, Sleep)>,
+    counters: Arc<StorageCounters>,
+    bus: Option<Arc<dyn InvalidationBus<D::Query>>>,
+    origin: u64,
+    /// Dropped with the last clone of the storage, which ends its background tasks
+    shutdown: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    #[cfg(feature = "serde")]
+    l2: Option<Arc<L2Tier<D>>>,
+    /// Expiry of the field index, also passed on to the second level cache
+    time_to_live: Option<Duration>,
+    schema: PhantomData<fn() -> S>,
+}
+
+impl<Exc: DataQueryExecutor<D>, D: DataMarker, S> Clone for Storage<Exc, D, S> {
+    fn clone(&self) -> Self {
+        Self {
+            executor: Arc::clone(&self.executor),
+            data: Arc::clone(&self.data),
+            query_cache: Arc::clone(&self.query_cache),
+            missing: self.missing.clone(),
+            pending: Arc::clone(&self.pending),
+            writes: Arc::clone(&self.writes),
+            query: Arc::clone(&self.query),
+            fields: Arc::clone(&self.fields),
+            references: Arc::clone(&self.references),
+            batch: self.batch.clone(),
+            timeout: self.timeout.clone(),
+            counters: Arc::clone(&self.counters),
+            bus: self.bus.clone(),
+            origin: self.origin,
+            shutdown: Arc::clone(&self.shutdown),
+            #[cfg(feature = "serde")]
+            l2: self.l2.clone(),
+            time_to_live: self.time_to_live,
+            schema: PhantomData,
+        }
+    }
+}
+
+impl<Exc, D, S> Storage<Exc, D, S>
+where
+    Exc: DataQueryExecutor<D> + 'static,
+    Exc::Id: 'static,
+    Exc::Error: Send + Sync + 'static,
+    D: DataMarker + Send + Sync + 'static,
+    D::Query: Clone + 'static,
+    S: StorageSchema<Exc, D>,
+{
+    pub fn new(executor: Exc) -> Self {
+        Self::with_config(executor, Self::default_config())
+    }
+
+    pub fn with_config(executor: Exc, config: StorageConfig<D>) -> Self {
+        let data = config.build_data_cache();
+        let queries = config.build_data_cache();
+        Self::with_backends(executor, config, data, queries)
+    }
+
+    /// Keeps entities and query results in the given backends instead of the ones selected
+    /// by the config. Misses still go to the backend of the config.
+    ///
+    /// The indexes of entities evicted by the data backend are dropped through
+    /// [`CacheBackend::set_eviction_listener`].
+    pub fn with_backends(
+        executor: Exc,
+        config: StorageConfig<D>,
+        data: impl CacheBackend<Exc::Id, Data<D>> + 'static,
+        queries: impl CacheBackend<D::Query, Data<D>> + 'static,
+    ) -> Self {
+        let counters = Arc::new(StorageCounters::new(S::name()));
+        let executor = Arc::new(executor);
+        let timeout = config.timeout.clone();
+        let batch = config.batch_window.clone().map(|(window, sleep)| {
+            let executor = Arc::clone(&executor);
+            let counters = Arc::clone(&counters);
+            let timeout = timeout.clone();
+            Arc::new(BatchLoader::new(window, sleep, move |ids| {
+                let executor = Arc::clone(&executor);
+                let counters = Arc::clone(&counters);
+                let timeout = timeout.clone();
+                async move {
+                    let values = counters
+                        .instrument_batch(
+                            ids.len(),
+                            Self::load_many(&executor, &counters, timeout.as_ref(), &ids),
+                        )
+                        .await?;
+                    Ok(values
+                        .into_iter()
+                        .map(|data| (executor.get_id(&data), Data::new(data)))
+                        .collect())
+                }
+                .boxed()
+            }))
+        });
+        let query = Arc::new(DashMap::new());
+        let fields = Arc::new(DashMap::new());
+        let references = Arc::new(DashMap::new());
+        let query_cache: Arc<dyn CacheBackend<D::Query, Data<D>>> = Arc::new(queries);
+        let missing = config.build_negative_cache();
+        let data: Arc<dyn CacheBackend<Exc::Id, Data<D>>> = Arc::new(data);
+        data.set_eviction_listener({
+            let query = Arc::clone(&query);
+            let fields = Arc::clone(&fields);
+            let references = Arc::clone(&references);
+            let query_cache = Arc::clone(&query_cache);
+            let counters = Arc::clone(&counters);
+            Arc::new(move |id: &Exc::Id, evicted: &Data<D>| {
+                counters.eviction();
+                Self::evict_queries(&query, &fields, &references, &*query_cache, id, evicted);
+            })
+        });
+        Self {
+            executor,
+            data,
+            query_cache,
+            missing,
+            pending: Arc::new(DashMap::new()),
+            writes: Arc::new(AtomicU64::new(0)),
+            query,
+            fields,
+            references,
+            batch,
+            timeout,
+            counters,
+            bus: None,
+            origin: new_origin(),
+            shutdown: Arc::default(),
+            #[cfg(feature = "serde")]
+            l2: None,
+            time_to_live: config.get_time_to_live(),
+            schema: PhantomData,
+        }
+    }
+
+    pub fn default_config() -> StorageConfig<D> {
+        S::default_config()
+    }
+
+    /// Publishes changes and invalidations to other storage instances through the bus.
+    ///
+    /// Remote events are only applied while the future returned by
+    /// [`Storage::invalidation_listener`] is running, so it has to be spawned next to the
+    /// storage.
+    pub fn with_invalidation_bus(mut self, bus: Arc<dyn InvalidationBus<D::Query>>) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// Consults the second level cache before the executor and stores the results of the
+    /// executor in it, with the time to live of the storage.
+    ///
+    /// Keys are prefixed with `namespace`, which has to stay the same across builds and
+    /// processes sharing the cache, and must differ between the entities stored in it.
+    #[cfg(feature = "serde")]
+    pub fn with_l2_cache(mut self, cache: Arc<dyn L2Cache>, namespace: impl Into<String>) -> Self
+    where
+        D: serde::Serialize + serde::de::DeserializeOwned,
+        D::Query: serde::Serialize,
+    {
+        self.l2 = Some(Arc::new(L2Tier {
+            cache,
+            namespace: namespace.into(),
+            time_to_live: self.time_to_live,
+            encode: |data| serde_json::to_vec(data),
+            decode: |value| serde_json::from_slice(value),
+            encode_query: |query| serde_json::to_string(query),
+        }));
+        self
+    }
+
+    /// Subscribes to the invalidation bus and returns a future, which applies remote
+    /// invalidations until the bus closes or the last clone of the storage is dropped. The
+    /// future does nothing unless it is polled, so spawn it on a runtime, e.g.
+    /// `tokio::spawn(storage.invalidation_listener())`. Events published before this call
+    /// are not seen by the listener.
+    pub fn invalidation_listener(&self) -> impl Future<Output = ()> + Send + 'static {
+        let (storage, dropped) = self.detached();
+        let events = self.bus.as_ref().map(|bus| bus.subscribe());
+        async move {
+            let Some(events) = events else {
+                return;
+            };
+            let mut events = events.take_until(dropped);
+            while let Some(event) = events.next().await {
+                if event.origin == storage.origin {
+                    continue;
+                }
+                for query in &event.queries {
+                    storage.invalidate_local(query).await;
+                }
+            }
+        }
+    }
+
+    /// Sets the `datacache_entries` gauges every `interval` until the last clone of the
+    /// storage is dropped. Counting the entries can take time with some backends, so the
+    /// gauges are not updated on every change. Spawn the future like
+    /// [`Storage::invalidation_listener`].
+    #[cfg(feature = "metrics")]
+    pub fn metrics_reporter<F>(
+        &self,
+        interval: Duration,
+        sleep: impl Fn(Duration) -> F + Send + Sync + 'static,
+    ) -> impl Future<Output = ()> + Send + 'static
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (storage, mut dropped) = self.detached();
+        async move {
+            loop {
+                storage.stats();
+                if let Either::Right(_) =
+                    future::select(sleep(interval).boxed(), &mut dropped).await
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// A clone for a background task, which keeps neither the bus nor the shutdown signal of
+    /// the storage alive. The receiver completes once the last other clone was dropped.
+    fn detached(&self) -> (Self, oneshot::Receiver<()>) {
+        let (shutdown, dropped) = oneshot::channel();
+        self.shutdown.lock().unwrap().push(shutdown);
+        let storage = Self {
+            bus: None,
+            shutdown: Arc::default(),
+            ..self.clone()
+        };
+        (storage, dropped)
+    }
+
+    /// Also sets the `datacache_entries` gauges with the `metrics` feature.
+    pub fn stats(&self) -> StorageStats {
+        self.counters.stats(
+            self.data.entry_count(),
+            self.query_cache.entry_count(),
+            self.missing
+                .as_ref()
+                .map_or(0, |missing| missing.entry_count()),
+            self.query.len(),
+            self.fields.len(),
+        )
+    }
+
+    /// Runs pending maintenance tasks (evictions and eviction notifications) of the caches.
+    pub fn sync(&self) {
+        self.data.sync();
+        self.query_cache.sync();
+        if let Some(missing) = &self.missing {
+            missing.sync();
+        }
+    }
+
+    /// Like `find_one`, but looks past a cached miss of the query. The miss is only dropped
+    /// once the entity was found.
+    pub async fn find_one_bypass_negative(
+        &self,
+        query: &D::Query,
+    ) -> Result<Data<D>, Error<Exc::Error>> {
+        self.counters
+            .instrument(
+                "find_one_bypass_negative",
+                Some(D::query_name(query)),
+                async move {
+                    let data = self.find(query, false).await?.ok_or(Error::NotFound)?;
+                    self.forget_missing(query).await;
+                    Ok(data)
+                },
+            )
+            .await
+    }
+
+    /// Answers `find_optional`, cached misses are only consulted with `negative`.
+    async fn find(
+        &self,
+        query: &D::Query,
+        negative: bool,
+    ) -> Result<Option<Data<D>>, Error<Exc::Error>> {
+        if let Some(missing) = self.missing.as_ref().filter(|_| negative) {
+            if missing.contains_key(query) {
+                self.counters.hit();
+                return Ok(None);
+            }
+        }
+        if let Some(id) = self.find_id(query) {
+            if let Some(data) = self.find_cached(&id) {
+                return Ok(Some(data));
+            }
+            if let Some(batch) = &self.batch {
+                return self.find_batched(batch, query, id).await;
+            }
+        }
+        if let Some(data) = self.query_cache.get(query) {
+            self.counters.hit();
+            return Ok(Some(data));
+        }
+        self.counters.miss();
+        let version = self.write_version();
+        if let Some(data) = self.l2_get(query).await {
+            self.cache_loaded(version, Some(query), &data).await;
+            return Ok(Some(data));
+        }
+        let pending = self.load_query(query);
+        let data = pending.clone().await;
+        self.pending
+            .remove_if(query, |_, other| other.ptr_eq(&pending));
+        let data = data?;
+        match &data {
+            Some(data) => {
+                self.l2_set(query, data).await;
+                self.cache_loaded(version, Some(query), data).await;
+            }
+            // Misses only live in the negative cache, which has its own expiry
+            None => self.cache_missing(version, query).await,
+        }
+        Ok(data)
+    }
+
+    async fn publish(&self, queries: Vec<D::Query>) {
+        if let Some(bus) = &self.bus {
+            bus.publish(Invalidation {
+                origin: self.origin,
+                queries,
+            })
+            .await;
+        }
+    }
+
+    /// Drops everything cached for the query without asking the executor.
+    async fn invalidate_local(&self, query: &D::Query) {
+        self.begin_write();
+        let mut ids: Vec<_> = self.find_id(query).into_iter().collect();
+        if let Some((_, field_ids)) = self.fields.remove(query) {
+            ids.extend(field_ids.ids);
+        }
+        if let Some(data) = self.query_cache.get(query) {
+            ids.push(self.executor.get_id(&data));
+        }
+        self.query.remove(query);
+        self.query_cache.invalidate(query);
+        self.forget_missing(query).await;
+        for id in ids {
+            self.remove_data(&id).await;
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    async fn l2_get(&self, query: &D::Query) -> Option<Data<D>> {
+        self.l2.as_ref()?.get(query).await
+    }
+
+    #[cfg(not(feature = "serde"))]
+    async fn l2_get(&self, _query: &D::Query) -> Option<Data<D>> {
+        None
+    }
+
+    /// Stores the entity under the query and its id query.
+    #[cfg(feature = "serde")]
+    async fn l2_set(&self, query: &D::Query, data: &Data<D>) {
+        if let Some(l2) = &self.l2 {
+            let id_query = S::id_query(&self.executor.get_id(data));
+            if &id_query != query {
+                l2.set(&id_query, data).await;
+            }
+            l2.set(query, data).await;
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    async fn l2_set(&self, _query: &D::Query, _data: &Data<D>) {}
+
+    #[cfg(feature = "serde")]
+    async fn l2_delete(&self, queries: &[D::Query]) {
+        if let Some(l2) = &self.l2 {
+            l2.delete(queries).await;
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    async fn l2_delete(&self, _queries: &[D::Query]) {}
+
+    /// Drops the lookups in flight and marks the results of running ones as stale, called
+    /// once the executor changed entities and before the caches are updated.
+    fn begin_write(&self) {
+        self.pending.clear();
+        self.writes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn write_version(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// Caches an entity loaded by a lookup which started at `version`. A write since then
+    /// might have changed the entity, so it is dropped again in that case, from the second
+    /// level cache as well.
+    async fn cache_loaded(&self, version: u64, query: Option<&D::Query>, data: &Data<D>) {
+        if let Some(query) = query {
+            self.query_cache.insert(query.clone(), data.clone());
+        }
+        self.insert_data(data.clone()).await;
+        if self.write_version() == version {
+            return;
+        }
+        let mut queries = data.create_queries();
+        if let Some(query) = query {
+            self.query_cache.invalidate(query);
+            queries.push(query.clone());
+        }
+        self.remove_data(&self.executor.get_id(data)).await;
+        self.l2_delete(&queries).await;
+    }
+
+    async fn cache_missing(&self, version: u64, query: &D::Query) {
+        if let Some(missing) = &self.missing {
+            missing.insert(query.clone(), ());
+            if self.write_version() != version {
+                missing.invalidate(query);
+            }
+        }
+    }
+
+    async fn forget_missing(&self, query: &D::Query) {
+        if let Some(missing) = &self.missing {
+            missing.invalidate(query);
+        }
+    }
+
+    fn evict_queries(
+        query: &DashMap<D::Query, Exc::Id>,
+        fields: &FieldIndex<Exc, D>,
+        references: &DashMap<DataReference, IdSet<Exc, D>>,
+        query_cache: &dyn CacheBackend<D::Query, Data<D>>,
+        id: &Exc::Id,
+        data: &Data<D>,
+    ) {
+        for q in data.create_queries() {
+            query.remove_if(&q, |_, v| v == id);
+            // The set might not be complete anymore once an entity changed, so drop it entirely
+            fields.remove(&q);
+            query_cache.invalidate(&q);
+        }
+        Self::unindex_references(references, id, data);
+    }
+
+    fn unindex_references(
+        references: &DashMap<DataReference, IdSet<Exc, D>>,
+        id: &Exc::Id,
+        data: &Data<D>,
+    ) {
+        for reference in data.references() {
+            if let Some(mut ids) = references.get_mut(&reference) {
+                ids.remove(id);
+            }
+            references.remove_if(&reference, |_, ids| ids.is_empty());
+        }
+    }
+
+    fn find_id(&self, query: &D::Query) -> Option<Exc::Id> {
+        if let Some(id) = S::query_id(query) {
+            return Some(id);
+        }
+        if !S::is_unique_query(query) {
+            return None;
+        }
+        self.query.get(query).map(|id| id.value().clone())
+    }
+
+    fn find_cached(&self, id: &Exc::Id) -> Option<Data<D>> {
+        let data = self.data.get(id)?;
+        self.counters.hit();
+        Some(data)
+    }
+
+    /// Loads the entity through the batch loader, ids which are gone are cached as misses
+    /// of the query.
+    async fn find_batched(
+        &self,
+        batch: &StorageLoader<Exc, D>,
+        query: &D::Query,
+        id: Exc::Id,
+    ) -> Result<Option<Data<D>>, Error<Exc::Error>> {
+        self.counters.miss();
+        let version = self.write_version();
+        if let Some(data) = self.l2_get(query).await {
+            self.cache_loaded(version, None, &data).await;
+            return Ok(Some(data));
+        }
+        let data = batch.load(id).await?;
+        match &data {
+            Some(data) => {
+                self.l2_set(query, data).await;
+                self.cache_loaded(version, None, data).await;
+            }
+            None => self.cache_missing(version, query).await,
+        }
+        Ok(data)
+    }
 
-impl<T: DataMarker> Data<T> {
-    pub fn new(data: T) -> Self {
-        Self(Arc::new(data))
+    async fn find_field_ids(&self, query: &D::Query) -> Result<Vec<Exc::Id>, Error<Exc::Error>> {
+        if let Some(field) = self.fields.get(query) {
+            if !field.is_expired() {
+                return Ok(field.ids.iter().cloned().collect());
+            }
+        }
+        self.fields.remove_if(query, |_, field| field.is_expired());
+        if let Some(missing) = &self.missing {
+            if missing.contains_key(query) {
+                return Ok(vec![]);
+            }
+        }
+        let version = self.write_version();
+        let ids = self
+            .counters
+            .execute(
+                self.timeout.as_ref(),
+                self.executor.find_all_ids(Some(query)),
+            )
+            .await?;
+        if ids.is_empty() {
+            // Nothing would ever extend an empty set, so it expires with the other misses
+            self.cache_missing(version, query).await;
+            return Ok(ids);
+        }
+        let field = FieldIds {
+            ids: ids.iter().cloned().collect(),
+            expires: self.time_to_live.map(|duration| Instant::now() + duration),
+        };
+        self.fields.insert(query.clone(), field);
+        if self.write_version() != version {
+            self.fields.remove(query);
+        }
+        Ok(ids)
     }
-}
 
-impl<T: DataMarker> DataMarker for Data<T> {
-    type Query = T::Query;
+    async fn insert_data(&self, data: Data<D>) {
+        let id = self.executor.get_id(&data);
+        let queries = data.create_queries();
+        if let Some(old) = self.data.get(&id) {
+            for query in old.create_queries() {
+                if queries.contains(&query) {
+                    continue;
+                }
+                self.query.remove_if(&query, |_, other| other == &id);
+                if let Some(mut field) = self.fields.get_mut(&query) {
+                    field.ids.remove(&id);
+                }
+                self.query_cache.invalidate(&query);
+            }
+            Self::unindex_references(&self.references, &id, &old);
+        }
+        for reference in data.references() {
+            self.references
+                .entry(reference)
+                .or_default()
+                .insert(id.clone());
+        }
+        for query in queries {
+            if S::is_unique_query(&query) {
+                self.query.insert(query, id.clone());
+            } else if S::is_field_query(&query) {
+                // Only extend sets which are already complete
+                if let Some(mut field) = self.fields.get_mut(&query) {
+                    field.ids.insert(id.clone());
+                }
+            }
+        }
+        self.data.insert(id.clone(), data.clone());
+        // Backends which keep nothing, or evicted the entity right away, must not leave an
+        // index behind which resolves to it
+        if !self.data.contains_key(&id) {
+            Self::evict_queries(
+                &self.query,
+                &self.fields,
+                &self.references,
+                &*self.query_cache,
+                &id,
+                &data,
+            );
+        }
+    }
 
-    fn create_queries(&self) -> Vec<Self::Query> {
-        T::create_queries(&self.0)
+    async fn store_data(&self, data: D) -> Data<D> {
+        self.begin_write();
+        let data = Data::new(data);
+        let id = self.executor.get_id(&data);
+        for query in data.create_queries() {
+            self.query_cache.invalidate(&query);
+            self.forget_missing(&query).await;
+        }
+        // Other instances might have cached the entity under its old or its new queries
+        let mut stale = self.cached_queries(&id).await;
+        self.insert_data(data.clone()).await;
+        let queries = data.create_queries();
+        stale.extend(queries.iter().cloned());
+        self.l2_delete(&stale).await;
+        self.l2_set(&S::id_query(&id), &data).await;
+        self.publish(queries).await;
+        data
     }
-}
 
-impl<T> Deref for Data<T> {
-    type Target = T;
+    async fn remove_data(&self, id: &Exc::Id) {
+        if let Some(data) = self.data.get(id) {
+            Self::evict_queries(
+                &self.query,
+                &self.fields,
+                &self.references,
+                &*self.query_cache,
+                id,
+                &data,
+            );
+        }
+        self.data.invalidate(id);
+    }
 
-    fn deref(&self) -> &Self::Target {
-        self.0.deref()
+    /// Asks the executor for the query, or joins a lookup of the same query already in flight.
+    fn load_query(&self, query: &D::Query) -> PendingQuery<Exc, D> {
+        self.pending
+            .entry(query.clone())
+            .or_insert_with(|| {
+                let executor = Arc::clone(&self.executor);
+                let counters = Arc::clone(&self.counters);
+                let timeout = self.timeout.clone();
+                let query = query.clone();
+                async move {
+                    let data = counters
+                        .execute(timeout.as_ref(), executor.find_optional(&query))
+                        .await?;
+                    Ok(data.map(Data::new))
+                }
+                .boxed()
+                .shared()
+            })
+            .clone()
     }
-}
 
-impl<T> AsRef<T> for Data<T> {
-    fn as_ref(&self) -> &T {
-        self.0.as_ref()
+    /// Loads the entities through `find_many`, or one by one if the executor cannot load
+    /// several at once.
+    async fn load_many(
+        executor: &Exc,
+        counters: &StorageCounters,
+        timeout: Option<&(Duration, Sleep)>,
+        ids: &[Exc::Id],
+    ) -> Result<Vec<D>, Error<Exc::Error>> {
+        if let Some(values) = counters.execute(timeout, executor.find_many(ids)).await? {
+            return Ok(values);
+        }
+        let mut values = Vec::with_capacity(ids.len());
+        for id in ids {
+            let data = counters
+                .execute(timeout, executor.find_optional(&S::id_query(id)))
+                .await?;
+            values.extend(data);
+        }
+        Ok(values)
     }
-}
 
-impl<T> Borrow<T> for Data<T> {
-    fn borrow(&self) -> &T {
-        self.0.borrow()
+    /// The queries of the entity, if it is cached locally or in the second level cache. Other
+    /// instances might have stored it under any of them.
+    async fn cached_queries(&self, id: &Exc::Id) -> Vec<D::Query> {
+        if let Some(data) = self.data.get(id) {
+            return data.create_queries();
+        }
+        self.l2_get(&S::id_query(id))
+            .await
+            .map_or_else(Vec::new, |data| data.create_queries())
     }
-}
 
-impl<T> Clone for Data<T> {
-    fn clone(&self) -> Self {
-        Self(Arc::clone(&self.0))
+    fn remove_field_ids(&self, ids: &[Exc::Id]) {
+        for mut entry in self.fields.iter_mut() {
+            for id in ids {
+                entry.ids.remove(id);
+            }
+        }
     }
 }
 
-impl<T: Debug> Debug for Data<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Debug::fmt(&self.0, f)
+#[async_trait::async_trait]
+impl<Exc, D, S> DataStorage<Exc, D> for Storage<Exc, D, S>
+where
+    Exc: DataQueryExecutor<D> + 'static,
+    Exc::Id: 'static,
+    Exc::Error: Send + Sync + 'static,
+    D: DataMarker + Send + Sync + 'static,
+    D::Query: Clone + 'static,
+    S: StorageSchema<Exc, D>,
+{
+    async fn find_one(&self, query: &D::Query) -> Result<Data<D>, Error<Exc::Error>> {
+        self.counters
+            .instrument("find_one", Some(D::query_name(query)), async move {
+                self.find_optional(query).await?.ok_or(Error::NotFound)
+            })
+            .await
     }
-}
-impl<T: Display> Display for Data<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Display::fmt(&self.0, f)
+
+    async fn find_all(&self, query: Option<&D::Query>) -> Result<Vec<Data<D>>, Error<Exc::Error>> {
+        self.counters
+            .instrument("find_all", query.map(D::query_name), async move {
+                let ids = match query {
+                    Some(query) if S::is_field_query(query) => self.find_field_ids(query).await?,
+                    query => {
+                        self.counters
+                            .execute(self.timeout.as_ref(), self.executor.find_all_ids(query))
+                            .await?
+                    }
+                };
+                let mut values: Vec<_> = ids.iter().map(|id| self.data.get(id)).collect();
+                let missing: Vec<_> = ids
+                    .iter()
+                    .zip(values.iter())
+                    .filter(|(_, value)| value.is_none())
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                self.counters
+                    .lookups((ids.len() - missing.len()) as u64, missing.len() as u64);
+                if !missing.is_empty() {
+                    let version = self.write_version();
+                    let mut loaded = HashMap::with_capacity(missing.len());
+                    let found = Self::load_many(
+                        &self.executor,
+                        &self.counters,
+                        self.timeout.as_ref(),
+                        &missing,
+                    )
+                    .await?;
+                    for data in found {
+                        let id = self.executor.get_id(&data);
+                        let data = Data::new(data);
+                        self.cache_loaded(version, None, &data).await;
+                        loaded.insert(id, data);
+                    }
+                    for (id, value) in ids.iter().zip(values.iter_mut()) {
+                        if value.is_some() {
+                            continue;
+                        }
+                        *value = loaded.get(id).cloned();
+                        // Deleted since its id was listed
+                        if value.is_none() {
+                            self.cache_missing(version, &S::id_query(id)).await;
+                        }
+                    }
+                }
+                Ok(values.into_iter().flatten().collect())
+            })
+            .await
     }
-}
-impl<T: Hash> Hash for Data<T> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.hash(state)
+
+    async fn find_optional(&self, query: &D::Query) -> Result<Option<Data<D>>, Error<Exc::Error>> {
+        self.counters
+            .instrument("find_optional", Some(D::query_name(query)), async move {
+                self.find(query, true).await
+            })
+            .await
     }
-}
-impl<T: PartialEq> PartialEq<Data<T>> for Data<T> {
-    fn eq(&self, other: &Data<T>) -> bool {
-        self.0.eq(&other.0)
+
+    async fn delete(&self, query: &D::Query) -> Result<(), Error<Exc::Error>> {
+        self.counters
+            .instrument("delete", Some(D::query_name(query)), async move {
+                self.query.remove(query);
+                self.fields.remove(query);
+                self.query_cache.invalidate(query);
+                let ids = self
+                    .counters
+                    .execute(self.timeout.as_ref(), self.executor.delete(query))
+                    .await?;
+                self.begin_write();
+                self.remove_field_ids(&ids);
+                let mut queries = vec![query.clone()];
+                let mut l2_queries = Vec::new();
+                for id in ids {
+                    queries.push(S::id_query(&id));
+                    l2_queries.extend(self.cached_queries(&id).await);
+                    self.remove_data(&id).await;
+                }
+                l2_queries.extend(queries.iter().cloned());
+                self.l2_delete(&l2_queries).await;
+                self.publish(queries).await;
+                Ok(())
+            })
+            .await
     }
-}
 
-#[cfg(feature = "serde")]
-impl<T: serde::Serialize> serde::Serialize for Data<T> {
-    #[inline(always)]
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        T::serialize(&self.0, serializer)
+    async fn invalidate(&self, query: &D::Query) -> Result<(), Error<Exc::Error>> {
+        self.counters
+            .instrument("invalidate", Some(D::query_name(query)), async move {
+                self.begin_write();
+                self.query.remove(query);
+                self.fields.remove(query);
+                self.query_cache.invalidate(query);
+                self.forget_missing(query).await;
+                let ids = self
+                    .counters
+                    .execute(
+                        self.timeout.as_ref(),
+                        self.executor.find_all_ids(Some(query)),
+                    )
+                    .await?;
+                let mut queries = vec![query.clone()];
+                let mut l2_queries = Vec::new();
+                for id in ids {
+                    queries.push(S::id_query(&id));
+                    l2_queries.extend(self.cached_queries(&id).await);
+                    self.remove_data(&id).await;
+                }
+                l2_queries.extend(queries.iter().cloned());
+                self.l2_delete(&l2_queries).await;
+                self.publish(queries).await;
+                Ok(())
+            })
+            .await
     }
-}
 
-#[cfg(feature = "serde")]
-impl<'de, T: serde::Deserialize<'de> + DataMarker> serde::Deserialize<'de> for Data<T> {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        T::deserialize(deserializer).map(Data::new)
+    async fn invalidate_references(&self, reference: &DataReference) -> Vec<DataReference> {
+        self.counters
+            .instrument("invalidate_references", None, async move {
+                let Some((_, ids)) = self.references.remove(reference) else {
+                    return Vec::new();
+                };
+                self.begin_write();
+                let mut queries = Vec::new();
+                for id in ids {
+                    queries.extend(self.cached_queries(&id).await);
+                    self.remove_data(&id).await;
+                }
+                let references = queries.iter().map(DataReference::new::<D>).collect();
+                self.l2_delete(&queries).await;
+                self.publish(queries).await;
+                references
+            })
+            .await
     }
-}
 
-impl<T: Eq> Eq for Data<T> {}
+    async fn entity_queries(&self, query: &D::Query) -> Result<Vec<D::Query>, Error<Exc::Error>> {
+        self.counters
+            .instrument("entity_queries", Some(D::query_name(query)), async move {
+                let ids = self
+                    .counters
+                    .execute(
+                        self.timeout.as_ref(),
+                        self.executor.find_all_ids(Some(query)),
+                    )
+                    .await?;
+                let mut queries = Vec::new();
+                let mut missing = Vec::new();
+                for id in ids {
+                    match self.data.get(&id) {
+                        Some(data) => queries.extend(data.create_queries()),
+                        None => missing.push(id),
+                    }
+                }
+                if !missing.is_empty() {
+                    let loaded = Self::load_many(
+                        &self.executor,
+                        &self.counters,
+                        self.timeout.as_ref(),
+                        &missing,
+                    )
+                    .await?;
+                    queries.extend(loaded.iter().flat_map(D::create_queries));
+                }
+                Ok(queries)
+            })
+            .await
+    }
 
-impl<T: PartialOrd> PartialOrd<Data<T>> for Data<T> {
-    fn partial_cmp(&self, other: &Data<T>) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+    fn get_executor(&self) -> &Exc {
+        &self.executor
     }
 }
 
-impl<T: Ord> Ord for Data<T> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.cmp(&other.0)
+#[async_trait::async_trait]
+impl<Exc, D, S> DataWriteStorage<Exc, D> for Storage<Exc, D, S>
+where
+    Exc: DataWriteExecutor<D> + 'static,
+    Exc::Id: 'static,
+    Exc::Error: Send + Sync + 'static,
+    D: DataMarker + Send + Sync + 'static,
+    D::Query: Clone + 'static,
+    S: StorageSchema<Exc, D>,
+{
+    async fn insert(&self, data: D) -> Result<Data<D>, Error<Exc::Error>> {
+        self.counters
+            .instrument("insert", None, async move {
+                let data = self
+                    .counters
+                    .execute(self.timeout.as_ref(), self.executor.insert(data))
+                    .await?;
+                Ok(self.store_data(data).await)
+            })
+            .await
     }
-}
-impl<T: Pointer> Pointer for Data<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+
+    async fn update(&self, data: D) -> Result<Data<D>, Error<Exc::Error>> {
+        self.counters
+            .instrument("update", None, async move {
+                let data = self
+                    .counters
+                    .execute(self.timeout.as_ref(), self.executor.update(data))
+                    .await?;
+                Ok(self.store_data(data).await)
+            })
+            .await
     }
-}
 
-#[macro_export]
-macro_rules! storage_ref {
-    ($vis:vis $ident:ident) => {
-        $vis trait $ident: datacache::DataMarker + Sized {
-            type Exc: datacache::DataQueryExecutor<Self>;
-            type Storage: datacache::DataStorage<Self::Exc, Self> + Clone;
-        }
-    };
-    ($data:path: $ref:ident where Exc: $exc:path, Storage: $storage:path) => {
-        impl $ref for $data {
-            type Exc = $exc;
-            type Storage = $storage;
-        }
+    async fn upsert(&self, data: D) -> Result<Data<D>, Error<Exc::Error>> {
+        self.counters
+            .instrument("upsert", None, async move {
+                let data = self
+                    .counters
+                    .execute(self.timeout.as_ref(), self.executor.upsert(data))
+                    .await?;
+                Ok(self.store_data(data).await)
+            })
+            .await
     }
 }
 
-#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
-#[repr(transparent)]
-pub struct DataRef<D: DataMarker>(pub D::Query);
+#[async_trait::async_trait]
+pub trait LookupRef<D: DataMarker> {
+    async fn lookup(&self, reference: &DataRef<D>) -> Option<Data<D>>;
+}
 
-impl<D> Debug for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Debug,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("DataRef").field(&self.0).finish()
-    }
+/// Field types which can be resolved through a [`LookupRef`] implementation.
+pub trait Resolvable {
+    type Resolved: Send;
 }
-impl<D> Display for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Display,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Display::fmt(&self.0, f)
-    }
+
+#[async_trait::async_trait]
+pub trait Resolve<M: ?Sized + Sync>: Resolvable + Sync {
+    async fn resolve(&self, manager: &M) -> Self::Resolved;
 }
 
-impl<D> Clone for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Clone,
-{
-    #[inline(always)]
-    fn clone(&self) -> Self {
-        Self(self.0.clone())
-    }
+impl<D: DataMarker + Send + Sync> Resolvable for DataRef<D> {
+    type Resolved = Option<Data<D>>;
 }
 
-impl<D> PartialEq for DataRef<D>
+#[async_trait::async_trait]
+impl<D, M> Resolve<M> for DataRef<D>
 where
-    D: DataMarker,
-    D::Query: PartialEq,
+    D: DataMarker + Send + Sync,
+    M: LookupRef<D> + ?Sized + Sync,
 {
-    #[inline(always)]
-    fn eq(&self, other: &Self) -> bool {
-        self.0.eq(&other.0)
+    async fn resolve(&self, manager: &M) -> Self::Resolved {
+        manager.lookup(self).await
     }
 }
-impl<D> Eq for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Clone + Eq,
-{
+
+impl<T: Resolvable> Resolvable for Option<T> {
+    type Resolved = Option<T::Resolved>;
 }
-impl<D> PartialOrd for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Clone + PartialOrd,
-{
-    #[inline(always)]
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+
+#[async_trait::async_trait]
+impl<T: Resolve<M>, M: ?Sized + Sync> Resolve<M> for Option<T> {
+    async fn resolve(&self, manager: &M) -> Self::Resolved {
+        match self {
+            Some(value) => Some(value.resolve(manager).await),
+            None => None,
+        }
     }
 }
-impl<D> Ord for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Clone + Ord,
-{
-    #[inline(always)]
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.cmp(&other.0)
-    }
+
+impl<T: Resolvable> Resolvable for Vec<T> {
+    type Resolved = Vec<T::Resolved>;
 }
-impl<D> Hash for DataRef<D>
-where
-    D: DataMarker,
-    D::Query: Hash,
-{
-    #[inline(always)]
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.hash(state)
+
+#[async_trait::async_trait]
+impl<T: Resolve<M>, M: ?Sized + Sync> Resolve<M> for Vec<T> {
+    async fn resolve(&self, manager: &M) -> Self::Resolved {
+        future::join_all(self.iter().map(|value| value.resolve(manager))).await
     }
 }
 
-impl<D: DataMarker> DataRef<D> {
-    pub fn new(query: D::Query) -> Self {
-        Self(query)
-    }
+/// Implemented by `#[derive(DataMarker)]`, `References` holds the resolved value of every
+/// reference field.
+pub trait Populate: DataMarker {
+    type References: Send;
 }
 
 #[async_trait::async_trait]
-pub trait DataQueryExecutor<D: DataMarker>: Sized + Send + Sync {
-    type Error: Display;
-    type Id: Send + Sync + Hash + Eq + Clone;
+pub trait PopulateWith<M: ?Sized + Sync>: Populate + Sync {
+    /// Resolves all referenced entities concurrently.
+    async fn populate(&self, manager: &M) -> Self::References;
+}
 
-    fn get_id(&self, data: &D) -> Self::Id;
-    async fn find_one(&self, query: &D::Query) -> Result<D, Self::Error>;
-    // async fn find_all(&self, query: D::Query) -> Result<Vec<D>, Self::Error>;
-    async fn find_all_ids(&self, query: Option<&D::Query>) -> Result<Vec<Self::Id>, Self::Error>;
-    async fn find_optional(&self, query: &D::Query) -> Result<Option<D>, Self::Error>;
-    async fn delete(&self, data: &D::Query) -> Result<Vec<Self::Id>, Self::Error>;
+/// An entity together with all entities it references.
+pub struct Resolved<T: Populate> {
+    pub data: Data<T>,
+    pub references: T::References,
 }
 
-#[async_trait::async_trait]
-pub trait DataStorage<Exc: DataQueryExecutor<D>, D: DataMarker>: Send + Sync {
-    async fn find_one(&self, query: &D::Query) -> Result<Data<D>, Arc<Exc::Error>>;
-    async fn find_all(&self, query: Option<&D::Query>) -> Result<Vec<Data<D>>, Arc<Exc::Error>>;
-    async fn find_optional(&self, query: &D::Query) -> Result<Option<Data<D>>, Arc<Exc::Error>>;
+impl<T: Populate> Resolved<T> {
+    pub async fn new<M>(data: Data<T>, manager: &M) -> Self
+    where
+        M: ?Sized + Sync,
+        T: PopulateWith<M>,
+    {
+        let references = data.populate(manager).await;
+        Self { data, references }
+    }
+
+    pub async fn lookup<M>(manager: &M, reference: &DataRef<T>) -> Option<Self>
+    where
+        M: LookupRef<T> + ?Sized + Sync,
+        T: PopulateWith<M>,
+    {
+        let data = manager.lookup(reference).await?;
+        Some(Self::new(data, manager).await)
+    }
+}
 
-    async fn delete(&self, query: &D::Query) -> Result<(), Exc::Error>;
-    async fn invalidate(&self, query: &D::Query) -> Result<(), Exc::Error>;
+impl<T: Populate> Deref for Resolved<T> {
+    type Target = T;
 
-    fn get_executor(&self) -> &Exc;
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
 }
 
-#[async_trait::async_trait]
-pub trait LookupRef<D: DataMarker> {
-    async fn lookup(&self, reference: &DataRef<D>) -> Option<Data<D>>;
+impl<T> Debug for Resolved<T>
+where
+    T: Populate + Debug,
+    T::References: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolved")
+            .field("data", &self.data)
+            .field("references", &self.references)
+            .finish()
+    }
 }
 
 #[macro_export]
@@ -278,6 +2707,15 @@ macro_rules! storage_manager {
         $vis struct $ident {
             storage: std::collections::HashMap<std::any::TypeId, std::sync::Arc<dyn std::any::Any + Send + Sync>>,
             data: std::collections::HashMap<std::any::TypeId, std::any::TypeId>,
+            dependents: std::collections::HashMap<std::any::TypeId, Vec<std::any::TypeId>>,
+            cascades: std::collections::HashMap<
+                std::any::TypeId,
+                std::sync::Arc<
+                    dyn Fn(datacache::DataReference) -> datacache::__internal::BoxFuture<'static, Vec<datacache::DataReference>>
+                        + Send
+                        + Sync,
+                >,
+            >,
         }
 
         impl $ident {
@@ -285,11 +2723,42 @@ macro_rules! storage_manager {
                 Self {
                     storage: std::collections::HashMap::new(),
                     data: std::collections::HashMap::new(),
+                    dependents: std::collections::HashMap::new(),
+                    cascades: std::collections::HashMap::new(),
+                }
+            }
+
+            /// Invalidates the query and all cached entities which (transitively) reference it.
+            pub async fn invalidate_cascade<D: $ref + 'static>(
+                &self,
+                query: &D::Query,
+            ) -> Result<(), datacache::Error<<D::Exc as datacache::DataQueryExecutor<D>>::Error>> {
+                let mut pending = vec![datacache::DataReference::new::<D>(query)];
+                if let Some(storage) = self.get_for_data::<D>() {
+                    // Dependents might reference the entities by any of their queries
+                    let queries = datacache::DataStorage::entity_queries(storage, query).await?;
+                    pending.extend(queries.iter().map(datacache::DataReference::new::<D>));
+                    datacache::DataStorage::invalidate(storage, query).await?;
+                }
+                let mut visited = std::collections::HashSet::new();
+                while let Some(reference) = pending.pop() {
+                    if !visited.insert(reference) {
+                        continue;
+                    }
+                    let Some(dependents) = self.dependents.get(&reference.data_type()) else {
+                        continue;
+                    };
+                    for dependent in dependents {
+                        if let Some(cascade) = self.cascades.get(dependent) {
+                            pending.extend(cascade(reference).await);
+                        }
+                    }
                 }
+                Ok(())
             }
 
             pub fn get_for_data<D: $ref + 'static>(&self) -> Option<&D::Storage> {
-                let Some(id) = self.data.get(&std::any::TypeId::of::<D>()) else { return None };
+                let id = self.data.get(&std::any::TypeId::of::<D>())?;
                 self.storage
                     .get(id)
                     .map(|v| v.downcast_ref::<D::Storage>())
@@ -312,8 +2781,25 @@ macro_rules! storage_manager {
                 storage: T,
             ) {
                 let id = std::any::TypeId::of::<T>();
-                self.data.insert(std::any::TypeId::of::<D>(), id.clone());
-                self.storage.insert(id, std::sync::Arc::new(storage));
+                let data = std::any::TypeId::of::<D>();
+                self.data.insert(data, id);
+                for referenced in <D as datacache::DataMarker>::referenced_types() {
+                    let dependents = self.dependents.entry(referenced).or_default();
+                    if !dependents.contains(&data) {
+                        dependents.push(data);
+                    }
+                }
+                let storage = std::sync::Arc::new(storage);
+                self.storage.insert(id, storage.clone());
+                self.cascades.insert(
+                    data,
+                    std::sync::Arc::new(move |reference| {
+                        let storage = std::sync::Arc::clone(&storage);
+                        std::boxed::Box::pin(async move {
+                            datacache::DataStorage::invalidate_references(storage.as_ref(), &reference).await
+                        })
+                    }),
+                );
             }
 
             pub fn get_and_remove<S: Send + Sync + 'static>(&mut self) -> Option<std::sync::Arc<S>> {
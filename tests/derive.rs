@@ -0,0 +1,17 @@
+#[test]
+fn test_derive() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/tuple_struct.rs");
+    t.pass("tests/ui/enum_id.rs");
+    t.compile_fail("tests/ui/union.rs");
+    t.compile_fail("tests/ui/unsupported_attribute.rs");
+    t.compile_fail("tests/ui/unknown_composite_field.rs");
+    t.compile_fail("tests/ui/rename_without_queryable.rs");
+    t.compile_fail("tests/ui/enum_missing_id.rs");
+    t.compile_fail("tests/ui/id_mismatch.rs");
+    t.compile_fail("tests/ui/enum_type_mismatch.rs");
+    t.compile_fail("tests/ui/enum_tuple_without_rename.rs");
+    t.compile_fail("tests/ui/rename_shadows_constructor.rs");
+    t.compile_fail("tests/ui/shared_string.rs");
+    t.compile_fail("tests/ui/unique_mismatch.rs");
+}
@@ -1,12 +1,26 @@
 use std::convert::Infallible;
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::hash::Hash;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use datacache::Data;
+use datacache::DataId;
 use datacache::DataMarker;
 use datacache::DataQueryExecutor;
 use datacache::DataRef;
+use datacache::DataStorage;
+use datacache::DataWriteExecutor;
+use datacache::DataWriteStorage;
+use datacache::InvalidationBus;
 use datacache::LookupRef;
+use datacache::PopulateWith;
+use datacache::StorageConfig;
+use futures_util::StreamExt;
 
 #[test]
 fn test_get_storage_by_data() {
@@ -47,20 +61,21 @@ impl DataQueryExecutor<MacroData> for MacroExecutor {
         &self,
         _query: Option<&MacroDataQuery>,
     ) -> Result<Vec<Self::Id>, Self::Error> {
-        todo!()
+        // 8 is listed, but gone by the time it is loaded
+        Ok(vec![7, 8])
     }
     async fn find_optional(
         &self,
         query: &MacroDataQuery,
     ) -> Result<Option<MacroData>, Self::Error> {
         if let MacroDataQuery::id(id) = query {
-            if id == &7 {
-                Ok(Some(MacroData {
+            match id {
+                7 => Ok(Some(MacroData {
                     id: 7,
                     slug: "Test Data".into(),
-                }))
-            } else {
-                panic!("Only id 7 lookup");
+                })),
+                8 => Ok(None),
+                _ => panic!("Only id 7 and 8 lookups"),
             }
         } else {
             panic!("Slug lookup not tested")
@@ -102,7 +117,7 @@ datacache::storage!(
     MacroDataStorage(MacroExecutor, MacroData),
     id(id: i32),
     unique(),
-    fields(d1: String)
+    fields()
 );
 datacache::storage!(
     OtherDataStorage(OtherExecutor, OtherData),
@@ -110,6 +125,279 @@ datacache::storage!(
     unique(),
     fields()
 );
+datacache::storage!(
+    UncachedDataStorage(MacroExecutor, MacroData),
+    id(id: i32),
+    unique(),
+    fields(),
+    config(backend = datacache::BackendKind::Noop)
+);
+datacache::storage!(
+    BoundedDataStorage(MacroExecutor, MacroData),
+    id(id: i32),
+    unique(),
+    fields(),
+    config(
+        max_capacity = 100,
+        time_to_live = Duration::from_secs(60),
+        timeout = (Duration::from_secs(5), tokio::time::sleep),
+        weigher = |data: &MacroData| data.slug.len() as u32
+    )
+);
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Member {
+    #[datacache(id)]
+    id: i32,
+    #[datacache(queryable(unique))]
+    slug: String,
+    #[datacache(queryable)]
+    group: String,
+}
+
+impl Member {
+    fn new(id: i32, slug: &str, group: &str) -> Self {
+        Self {
+            id,
+            slug: slug.into(),
+            group: group.into(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemberExecutor {
+    members: Arc<Mutex<Vec<Member>>>,
+    find_calls: AtomicUsize,
+    find_all_ids_calls: AtomicUsize,
+    find_many_calls: AtomicUsize,
+    delay: Option<Duration>,
+}
+
+impl MemberExecutor {
+    fn with_members(members: Vec<Member>) -> Self {
+        Self {
+            members: Arc::new(Mutex::new(members)),
+            ..Default::default()
+        }
+    }
+
+    fn sharing(other: &MemberExecutor) -> Self {
+        Self {
+            members: Arc::clone(&other.members),
+            ..Default::default()
+        }
+    }
+
+    fn find(&self, query: &MemberQuery) -> Vec<Member> {
+        self.members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|member| member.create_queries().contains(query))
+            .cloned()
+            .collect()
+    }
+}
+
+#[datacache::__internal::async_trait]
+impl DataQueryExecutor<Member> for MemberExecutor {
+    type Error = String;
+    type Id = i32;
+    fn get_id(&self, data: &Member) -> Self::Id {
+        data.id
+    }
+    async fn find_many(&self, ids: &[Self::Id]) -> Result<Option<Vec<Member>>, Self::Error> {
+        self.find_many_calls.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(
+            self.members
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|member| ids.contains(&member.id))
+                .cloned()
+                .collect(),
+        ))
+    }
+    async fn find_one(&self, query: &MemberQuery) -> Result<Member, Self::Error> {
+        self.find_optional(query)
+            .await?
+            .ok_or_else(|| format!("{query:?} not found"))
+    }
+    async fn find_all_ids(
+        &self,
+        query: Option<&MemberQuery>,
+    ) -> Result<Vec<Self::Id>, Self::Error> {
+        self.find_all_ids_calls.fetch_add(1, Ordering::SeqCst);
+        Ok(match query {
+            Some(query) => self.find(query),
+            None => self.members.lock().unwrap().clone(),
+        }
+        .into_iter()
+        .map(|member| member.id)
+        .collect())
+    }
+    async fn find_optional(&self, query: &MemberQuery) -> Result<Option<Member>, Self::Error> {
+        self.find_calls.fetch_add(1, Ordering::SeqCst);
+        let found = self.find(query).into_iter().next();
+        // A slow response still carries the state from the time of the query
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(found)
+    }
+    async fn delete(&self, query: &MemberQuery) -> Result<Vec<Self::Id>, Self::Error> {
+        let mut members = self.members.lock().unwrap();
+        let ids = members
+            .iter()
+            .filter(|member| member.create_queries().contains(query))
+            .map(|member| member.id)
+            .collect();
+        members.retain(|member| !member.create_queries().contains(query));
+        Ok(ids)
+    }
+}
+
+#[datacache::__internal::async_trait]
+impl DataWriteExecutor<Member> for MemberExecutor {
+    async fn insert(&self, data: Member) -> Result<Member, Self::Error> {
+        let mut members = self.members.lock().unwrap();
+        if members.iter().any(|member| member.id == data.id) {
+            return Err(format!("{} already exists", data.id));
+        }
+        members.push(data.clone());
+        Ok(data)
+    }
+    async fn update(&self, data: Member) -> Result<Member, Self::Error> {
+        let mut members = self.members.lock().unwrap();
+        match members.iter_mut().find(|member| member.id == data.id) {
+            Some(member) => {
+                *member = data.clone();
+                Ok(data)
+            }
+            None => Err(format!("{} not found", data.id)),
+        }
+    }
+    async fn upsert(&self, data: Member) -> Result<Member, Self::Error> {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|member| member.id != data.id);
+        members.push(data.clone());
+        Ok(data)
+    }
+}
+
+/// Keeps the entities of the remaining tests, which are identified by their [`DataId`].
+struct MemoryExecutor<T> {
+    entities: Mutex<Vec<T>>,
+    find_calls: AtomicUsize,
+}
+
+impl<T> MemoryExecutor<T> {
+    fn new(entities: Vec<T>) -> Self {
+        Self {
+            entities: Mutex::new(entities),
+            find_calls: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T: DataId + Clone> MemoryExecutor<T> {
+    fn find(&self, query: &T::Query) -> Vec<T> {
+        self.entities
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entity| entity.create_queries().contains(query))
+            .cloned()
+            .collect()
+    }
+}
+
+#[datacache::__internal::async_trait]
+impl<T> DataQueryExecutor<T> for MemoryExecutor<T>
+where
+    T: DataId + Clone + Send + Sync + 'static,
+    T::Id: Display,
+{
+    type Error = String;
+    type Id = T::Id;
+    fn get_id(&self, data: &T) -> Self::Id {
+        data.id()
+    }
+    async fn find_one(&self, query: &T::Query) -> Result<T, Self::Error> {
+        self.find_optional(query)
+            .await?
+            .ok_or_else(|| format!("{query:?} not found"))
+    }
+    async fn find_all_ids(&self, query: Option<&T::Query>) -> Result<Vec<Self::Id>, Self::Error> {
+        Ok(match query {
+            Some(query) => self.find(query),
+            None => self.entities.lock().unwrap().clone(),
+        }
+        .iter()
+        .map(DataId::id)
+        .collect())
+    }
+    async fn find_optional(&self, query: &T::Query) -> Result<Option<T>, Self::Error> {
+        self.find_calls.fetch_add(1, Ordering::SeqCst);
+        Ok(self.find(query).into_iter().next())
+    }
+    async fn delete(&self, query: &T::Query) -> Result<Vec<Self::Id>, Self::Error> {
+        let ids = self.find(query).iter().map(DataId::id).collect();
+        self.entities
+            .lock()
+            .unwrap()
+            .retain(|entity| !entity.create_queries().contains(query));
+        Ok(ids)
+    }
+}
+
+#[datacache::__internal::async_trait]
+impl<T> DataWriteExecutor<T> for MemoryExecutor<T>
+where
+    T: DataId + Clone + Send + Sync + 'static,
+    T::Id: Display,
+{
+    async fn insert(&self, data: T) -> Result<T, Self::Error> {
+        let mut entities = self.entities.lock().unwrap();
+        if entities.iter().any(|entity| entity.id() == data.id()) {
+            return Err(format!("{} already exists", data.id()));
+        }
+        entities.push(data.clone());
+        Ok(data)
+    }
+    async fn update(&self, data: T) -> Result<T, Self::Error> {
+        let mut entities = self.entities.lock().unwrap();
+        match entities.iter_mut().find(|entity| entity.id() == data.id()) {
+            Some(entity) => {
+                *entity = data.clone();
+                Ok(data)
+            }
+            None => Err(format!("{} not found", data.id())),
+        }
+    }
+    async fn upsert(&self, data: T) -> Result<T, Self::Error> {
+        let mut entities = self.entities.lock().unwrap();
+        entities.retain(|entity| entity.id() != data.id());
+        entities.push(data.clone());
+        Ok(data)
+    }
+}
+
+datacache::storage!(
+    MemberStorage(MemberExecutor, Member),
+    unique(slug: String),
+    fields(group: String)
+);
+
+fn members() -> MemberStorage {
+    MemberStorage::new(MemberExecutor::with_members(vec![
+        Member::new(1, "alice", "admins"),
+        Member::new(2, "bob", "users"),
+        Member::new(3, "carol", "admins"),
+    ]))
+}
 
 datacache::storage_ref!(pub StorageRef);
 datacache::storage_ref!(MacroData: StorageRef where Exc: MacroExecutor, Storage: MacroDataStorage);
@@ -148,3 +436,1675 @@ async fn test_lookup() {
         data,
     );
 }
+
+#[test]
+fn test_storage_config() {
+    let config = BoundedDataStorage::default_config();
+    assert_eq!(Some(100), config.get_max_capacity());
+    assert_eq!(Some(Duration::from_secs(60)), config.get_time_to_live());
+    assert_eq!(None, config.get_time_to_idle());
+    assert_eq!(Some(Duration::from_secs(5)), config.get_timeout());
+
+    let config = MacroDataStorage::default_config();
+    assert_eq!(None, config.get_max_capacity());
+}
+
+#[tokio::test]
+async fn test_with_config() {
+    let storage = BoundedDataStorage::with_config(
+        MacroExecutor,
+        StorageConfig::new().time_to_idle(Duration::from_secs(5)),
+    );
+    let data = DataStorage::find_optional(&storage, &MacroDataQuery::id(7)).await;
+    assert_eq!(7, data.unwrap().unwrap().id);
+}
+
+#[tokio::test]
+async fn test_unique_index() {
+    let storage = members();
+    let member = storage
+        .find_one(&MemberQuery::slug("bob".into()))
+        .await
+        .unwrap();
+    assert_eq!(2, member.id);
+    let member = storage.find_one(&MemberQuery::id(2)).await.unwrap();
+    assert_eq!("bob", member.slug);
+    let member = storage
+        .find_one(&MemberQuery::slug("bob".into()))
+        .await
+        .unwrap();
+    assert_eq!(2, member.id);
+    assert_eq!(1, storage.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_field_index() {
+    let storage = members();
+    let query = MemberQuery::group("admins".into());
+    let mut ids: Vec<i32> = storage
+        .find_all(Some(&query))
+        .await
+        .unwrap()
+        .iter()
+        .map(|member| member.id)
+        .collect();
+    ids.sort();
+    assert_eq!(vec![1, 3], ids);
+    assert_eq!(2, storage.find_all(Some(&query)).await.unwrap().len());
+    let executor = storage.get_executor();
+    assert_eq!(1, executor.find_all_ids_calls.load(Ordering::SeqCst));
+    assert_eq!(1, executor.find_many_calls.load(Ordering::SeqCst));
+
+    storage.invalidate(&query).await.unwrap();
+    assert_eq!(2, storage.find_all(Some(&query)).await.unwrap().len());
+    assert_eq!(3, executor.find_all_ids_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_field_index_empty() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![Member::new(1, "alice", "admins")]),
+        StorageConfig::new().negative_time_to_live(Duration::from_millis(20)),
+    );
+    let query = MemberQuery::group("guests".into());
+    assert!(storage.find_all(Some(&query)).await.unwrap().is_empty());
+    assert!(storage.find_all(Some(&query)).await.unwrap().is_empty());
+    let executor = storage.get_executor();
+    assert_eq!(1, executor.find_all_ids_calls.load(Ordering::SeqCst));
+    storage.sync();
+    let stats = storage.stats();
+    assert_eq!(0, stats.field_index_entries);
+    assert_eq!(1, stats.negative_entries);
+
+    // Members added behind the back of the storage show up once the miss expired
+    executor
+        .members
+        .lock()
+        .unwrap()
+        .push(Member::new(2, "bob", "guests"));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(1, storage.find_all(Some(&query)).await.unwrap().len());
+    assert_eq!(2, executor.find_all_ids_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_field_index_expiry() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![Member::new(1, "alice", "admins")]),
+        StorageConfig::new().time_to_live(Duration::from_millis(20)),
+    );
+    let query = MemberQuery::group("admins".into());
+    assert_eq!(1, storage.find_all(Some(&query)).await.unwrap().len());
+    assert_eq!(1, storage.stats().field_index_entries);
+
+    storage
+        .get_executor()
+        .members
+        .lock()
+        .unwrap()
+        .push(Member::new(2, "bob", "admins"));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(2, storage.find_all(Some(&query)).await.unwrap().len());
+}
+
+fn rename_member(storage: &MemberStorage, id: i32, slug: &str) {
+    let mut members = storage.get_executor().members.lock().unwrap();
+    let member = members.iter_mut().find(|member| member.id == id).unwrap();
+    member.slug = slug.into();
+}
+
+#[tokio::test]
+async fn test_renamed_slug() {
+    let storage = members();
+    let alice = MemberQuery::slug("alice".into());
+    assert!(storage.find_optional(&alice).await.unwrap().is_some());
+
+    rename_member(&storage, 1, "alicia");
+    let member = storage
+        .find_one(&MemberQuery::slug("alicia".into()))
+        .await
+        .unwrap();
+    assert_eq!(1, member.id);
+    assert_eq!(None, storage.find_optional(&alice).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_evicted_queries() {
+    let storage = members();
+    let alice = MemberQuery::slug("alice".into());
+    assert!(storage.find_optional(&alice).await.unwrap().is_some());
+
+    rename_member(&storage, 1, "alicia");
+    storage.invalidate(&MemberQuery::id(1)).await.unwrap();
+    assert_eq!(None, storage.find_optional(&alice).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_capacity_eviction() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "users"),
+            Member::new(3, "carol", "admins"),
+        ]),
+        StorageConfig::new().max_capacity(1),
+    );
+    for slug in ["alice", "bob", "carol"] {
+        storage
+            .find_one(&MemberQuery::slug(slug.into()))
+            .await
+            .unwrap();
+        storage.sync();
+    }
+    let stats = storage.stats();
+    assert_eq!(1, stats.data_entries);
+    assert_eq!(2, stats.evictions);
+    // Only the slug of the remaining entity is still indexed
+    assert_eq!(1, stats.index_entries);
+}
+
+#[tokio::test]
+async fn test_expiry_eviction() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![Member::new(1, "alice", "admins")]),
+        StorageConfig::new().time_to_live(Duration::from_millis(20)),
+    );
+    let admins = MemberQuery::group("admins".into());
+    assert_eq!(1, storage.find_all(Some(&admins)).await.unwrap().len());
+    storage
+        .find_one(&MemberQuery::slug("alice".into()))
+        .await
+        .unwrap();
+    assert_eq!(1, storage.stats().index_entries);
+    assert_eq!(1, storage.stats().field_index_entries);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    storage.sync();
+    let stats = storage.stats();
+    assert_eq!(0, stats.data_entries);
+    assert_eq!(1, stats.evictions);
+    assert_eq!(0, stats.index_entries);
+    assert_eq!(0, stats.field_index_entries);
+}
+
+#[tokio::test]
+async fn test_write_through() {
+    let storage = members();
+    let alice = MemberQuery::slug("alice".into());
+    assert!(storage.find_optional(&alice).await.unwrap().is_some());
+    let dave = MemberQuery::slug("dave".into());
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+
+    storage
+        .insert(Member::new(4, "dave", "users"))
+        .await
+        .unwrap();
+    storage
+        .update(Member::new(1, "alicia", "admins"))
+        .await
+        .unwrap();
+    let calls = storage.get_executor().find_calls.load(Ordering::SeqCst);
+
+    assert_eq!(4, storage.find_one(&dave).await.unwrap().id);
+    let alicia = MemberQuery::slug("alicia".into());
+    assert_eq!(1, storage.find_one(&alicia).await.unwrap().id);
+    assert_eq!(
+        "alicia",
+        storage.find_one(&MemberQuery::id(1)).await.unwrap().slug
+    );
+    assert_eq!(
+        calls,
+        storage.get_executor().find_calls.load(Ordering::SeqCst)
+    );
+    assert_eq!(None, storage.find_optional(&alice).await.unwrap());
+
+    let err = storage
+        .insert(Member::new(4, "dave", "users"))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        Some("4 already exists"),
+        err.executor_error().map(String::as_str)
+    );
+    assert_eq!("4 already exists", err.to_string());
+    let member = storage
+        .upsert(Member::new(4, "dave", "admins"))
+        .await
+        .unwrap();
+    assert_eq!("admins", member.group);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_write_during_lookup() {
+    let storage = MemberStorage::new(MemberExecutor {
+        members: Arc::new(Mutex::new(vec![Member::new(1, "alice", "admins")])),
+        delay: Some(Duration::from_millis(20)),
+        ..Default::default()
+    });
+    let alice = MemberQuery::slug("alice".into());
+    let (found, updated) = tokio::join!(storage.find_one(&alice), async {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        storage.update(Member::new(1, "alicia", "admins")).await
+    });
+    // The lookup answers with what it loaded, but must not cache it over the update
+    assert_eq!("alice", found.unwrap().slug);
+    assert_eq!("alicia", updated.unwrap().slug);
+    assert_eq!(
+        "alicia",
+        storage.find_one(&MemberQuery::id(1)).await.unwrap().slug
+    );
+    assert_eq!(None, storage.find_optional(&alice).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_find_all_batched() {
+    let storage = members();
+    storage.find_one(&MemberQuery::id(2)).await.unwrap();
+    let ids: Vec<i32> = storage
+        .find_all(None)
+        .await
+        .unwrap()
+        .iter()
+        .map(|member| member.id)
+        .collect();
+    assert_eq!(vec![1, 2, 3], ids);
+    let executor = storage.get_executor();
+    assert_eq!(1, executor.find_calls.load(Ordering::SeqCst));
+    assert_eq!(1, executor.find_many_calls.load(Ordering::SeqCst));
+
+    storage.find_all(None).await.unwrap();
+    assert_eq!(1, executor.find_many_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_find_many_fallback() {
+    let storage = MacroDataStorage::with_config(
+        MacroExecutor,
+        StorageConfig::new().batch_window(Duration::from_millis(5), tokio::time::sleep),
+    );
+    let data = storage.find_one(&MacroDataQuery::id(7)).await.unwrap();
+    assert_eq!("Test Data", data.slug);
+    assert_eq!(2, storage.stats().executor_calls);
+}
+
+#[tokio::test]
+async fn test_find_all_missing() {
+    let storage = MacroDataStorage::new(MacroExecutor);
+    let found = storage.find_all(None).await.unwrap();
+    assert_eq!(
+        vec![7],
+        found.iter().map(|data| data.id).collect::<Vec<_>>()
+    );
+    // The vanished entity is remembered as a miss
+    storage.sync();
+    assert_eq!(1, storage.stats().negative_entries);
+    assert_eq!(
+        None,
+        storage.find_optional(&MacroDataQuery::id(8)).await.unwrap()
+    );
+    assert_eq!(1, storage.stats().hits);
+}
+
+#[tokio::test]
+async fn test_batched_lookups() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "users"),
+            Member::new(3, "carol", "admins"),
+        ]),
+        StorageConfig::new().batch_window(Duration::from_millis(5), tokio::time::sleep),
+    );
+    let (alice, bob, carol, missing) = tokio::join!(
+        storage.find_one(&MemberQuery::id(1)),
+        storage.find_one(&MemberQuery::id(2)),
+        storage.find_optional(&MemberQuery::id(3)),
+        storage.find_optional(&MemberQuery::id(4)),
+    );
+    assert_eq!("alice", alice.unwrap().slug);
+    assert_eq!("bob", bob.unwrap().slug);
+    assert_eq!("carol", carol.unwrap().unwrap().slug);
+    assert_eq!(None, missing.unwrap());
+    let executor = storage.get_executor();
+    assert_eq!(1, executor.find_many_calls.load(Ordering::SeqCst));
+    // The id missing from the batch is a cached miss, not another lookup
+    assert_eq!(0, executor.find_calls.load(Ordering::SeqCst));
+    storage.sync();
+    assert_eq!(1, storage.stats().negative_entries);
+    assert_eq!(
+        None,
+        storage.find_optional(&MemberQuery::id(4)).await.unwrap()
+    );
+    assert_eq!(1, executor.find_many_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_negative_cache() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::default(),
+        StorageConfig::new().negative_time_to_live(Duration::from_millis(50)),
+    );
+    let dave = MemberQuery::slug("dave".into());
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+    assert!(matches!(
+        storage.find_one(&dave).await,
+        Err(datacache::Error::NotFound)
+    ));
+    let executor = storage.get_executor();
+    assert_eq!(1, executor.find_calls.load(Ordering::SeqCst));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+    assert_eq!(2, executor.find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_negative_cache_disabled() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::default(),
+        StorageConfig::new().negative_cache(false),
+    );
+    let dave = MemberQuery::slug("dave".into());
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+    storage
+        .get_executor()
+        .members
+        .lock()
+        .unwrap()
+        .push(Member::new(4, "dave", "users"));
+    assert_eq!(4, storage.find_one(&dave).await.unwrap().id);
+    assert_eq!(2, storage.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor {
+            delay: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+        StorageConfig::new().timeout(Duration::from_millis(20), tokio::time::sleep),
+    );
+    let err = storage
+        .find_optional(&MemberQuery::id(1))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, datacache::Error::Timeout));
+    assert_eq!(None, err.executor_error());
+}
+
+#[tokio::test]
+async fn test_find_one_after_cached_miss() {
+    let storage = members();
+    let calls = || storage.get_executor().find_calls.load(Ordering::SeqCst);
+    let dave = MemberQuery::slug("dave".into());
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+    // Used to panic with "Option should be Some(...)"
+    assert!(storage.find_one(&dave).await.unwrap_err().is_not_found());
+    assert_eq!(1, calls());
+    let stats = storage.stats();
+    assert_eq!((1, 1), (stats.hits, stats.misses));
+
+    // Still missing, the bypass asks the executor but keeps the miss
+    let err = storage.find_one_bypass_negative(&dave).await.unwrap_err();
+    assert!(err.is_not_found());
+    assert_eq!(2, calls());
+    storage.sync();
+    assert_eq!(1, storage.stats().negative_entries);
+
+    storage
+        .get_executor()
+        .members
+        .lock()
+        .unwrap()
+        .push(Member::new(4, "dave", "users"));
+    assert!(storage.find_one(&dave).await.unwrap_err().is_not_found());
+    assert_eq!(4, storage.find_one_bypass_negative(&dave).await.unwrap().id);
+    assert_eq!(4, storage.find_one(&dave).await.unwrap().id);
+    assert_eq!(3, calls());
+    storage.sync();
+    let stats = storage.stats();
+    assert_eq!(0, stats.negative_entries);
+    // Every lookup is counted once, either as a hit or as a miss
+    assert_eq!((3, 3), (stats.hits, stats.misses));
+}
+
+#[tokio::test]
+async fn test_stats() {
+    let storage = members();
+    storage
+        .find_one(&MemberQuery::slug("alice".into()))
+        .await
+        .unwrap();
+    storage.find_one(&MemberQuery::id(1)).await.unwrap();
+    storage
+        .find_optional(&MemberQuery::slug("dave".into()))
+        .await
+        .unwrap();
+    storage
+        .find_optional(&MemberQuery::slug("dave".into()))
+        .await
+        .unwrap();
+    storage.sync();
+
+    let stats = storage.stats();
+    assert_eq!(2, stats.hits);
+    assert_eq!(2, stats.misses);
+    assert_eq!(2, stats.executor_calls);
+    assert_eq!(0, stats.errors);
+    assert_eq!(1, stats.data_entries);
+    assert_eq!(1, stats.negative_entries);
+    assert_eq!(1, stats.index_entries);
+    assert_eq!(0, stats.field_index_entries);
+}
+
+/// Signals every event, which the subscriber has finished with.
+struct SignallingBus<Q> {
+    bus: datacache::LoopbackBus<Q>,
+    applied: futures_channel::mpsc::UnboundedSender<()>,
+}
+
+#[datacache::__internal::async_trait]
+impl<Q: Clone + Send + 'static> InvalidationBus<Q> for SignallingBus<Q> {
+    async fn publish(&self, event: datacache::Invalidation<Q>) {
+        self.bus.publish(event).await
+    }
+
+    fn subscribe(&self) -> futures_util::stream::BoxStream<'static, datacache::Invalidation<Q>> {
+        let events = self.bus.subscribe();
+        // The subscriber asks for the next event once it has applied the previous one
+        futures_util::stream::unfold(
+            (events, self.applied.clone(), false),
+            |(mut events, applied, delivered)| async move {
+                if delivered {
+                    let _ = applied.unbounded_send(());
+                }
+                let event = events.next().await?;
+                Some((event, (events, applied, true)))
+            },
+        )
+        .boxed()
+    }
+}
+
+#[tokio::test]
+async fn test_invalidation_bus() {
+    let (sender, mut applied) = futures_channel::mpsc::unbounded();
+    let bus = Arc::new(SignallingBus {
+        bus: datacache::LoopbackBus::new(),
+        applied: sender,
+    });
+    let local = members().with_invalidation_bus(bus.clone());
+    let remote = MemberStorage::new(MemberExecutor::sharing(local.get_executor()))
+        .with_invalidation_bus(bus.clone());
+    let listener = tokio::spawn(remote.invalidation_listener());
+
+    let alice = MemberQuery::slug("alice".into());
+    assert_eq!(1, remote.find_one(&alice).await.unwrap().id);
+    local
+        .update(Member::new(1, "alicia", "admins"))
+        .await
+        .unwrap();
+    local.delete(&MemberQuery::id(2)).await.unwrap();
+    // Both events are applied by the remote listener
+    applied.next().await.unwrap();
+    applied.next().await.unwrap();
+
+    assert_eq!(None, remote.find_optional(&alice).await.unwrap());
+    assert_eq!(
+        "alicia",
+        remote.find_one(&MemberQuery::id(1)).await.unwrap().slug
+    );
+    assert_eq!(
+        None,
+        remote.find_optional(&MemberQuery::id(2)).await.unwrap()
+    );
+    // The listener ends with the storage, even though the bus is still open
+    drop(remote);
+    tokio::time::timeout(Duration::from_secs(1), listener)
+        .await
+        .expect("listener outlived its storage")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_channel_bus() {
+    let (bus, mut outgoing) = datacache::ChannelBus::new();
+    let mut events = bus.subscribe();
+    let event = datacache::Invalidation {
+        origin: 1,
+        queries: vec![MemberQuery::id(1)],
+    };
+    bus.publish(event.clone()).await;
+    assert_eq!(Some(event.clone()), outgoing.next().await);
+
+    bus.deliver(event.clone());
+    assert_eq!(Some(event), events.next().await);
+}
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+struct Team {
+    #[datacache(id)]
+    id: i32,
+    #[datacache(references)]
+    lead: DataRef<Member>,
+    #[datacache(references)]
+    deputy: Option<DataRef<Member>>,
+}
+
+datacache::storage!(
+    TeamStorage(MemoryExecutor<Team>, Team),
+    id(id: i32),
+    unique(),
+    fields()
+);
+
+datacache::storage_ref!(Member: StorageRef where Exc: MemberExecutor, Storage: MemberStorage);
+datacache::storage_ref!(Team: StorageRef where Exc: MemoryExecutor<Team>, Storage: TeamStorage);
+
+#[tokio::test]
+async fn test_invalidate_cascade() {
+    let team = |id, lead, deputy: Option<i32>| Team {
+        id,
+        lead: DataRef::new(MemberQuery::id(lead)),
+        deputy: deputy.map(|deputy| DataRef::new(MemberQuery::id(deputy))),
+    };
+    assert_eq!(
+        vec![std::any::TypeId::of::<Member>(); 2],
+        Team::referenced_types()
+    );
+    assert_eq!(2, team(1, 1, Some(2)).references().len());
+
+    let mut storage = DataManager::new();
+    storage.register_storage(members());
+    storage.register_storage(TeamStorage::new(MemoryExecutor::new(vec![
+        team(1, 1, None),
+        team(2, 2, Some(1)),
+        team(3, 3, None),
+    ])));
+    let teams = storage.get_for_data::<Team>().unwrap();
+    for id in 1..=3 {
+        teams.find_one(&TeamQuery::id(id)).await.unwrap();
+    }
+    assert_eq!(3, teams.get_executor().find_calls.load(Ordering::SeqCst));
+
+    storage
+        .invalidate_cascade::<Member>(&MemberQuery::id(1))
+        .await
+        .unwrap();
+    for id in 1..=3 {
+        teams.find_one(&TeamQuery::id(id)).await.unwrap();
+    }
+    // Team 1 is led by and team 2 is deputised by member 1, team 3 stays cached
+    assert_eq!(5, teams.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_invalidate_cascade_by_other_query() {
+    let mut storage = DataManager::new();
+    storage.register_storage(members());
+    storage.register_storage(TeamStorage::new(MemoryExecutor::new(vec![
+        Team {
+            id: 1,
+            lead: DataRef::new(MemberQuery::by_slug("alice")),
+            deputy: None,
+        },
+        Team {
+            id: 2,
+            lead: DataRef::new(MemberQuery::by_slug("bob")),
+            deputy: None,
+        },
+    ])));
+    let teams = storage.get_for_data::<Team>().unwrap();
+    teams.find_one(&TeamQuery::id(1)).await.unwrap();
+    teams.find_one(&TeamQuery::id(2)).await.unwrap();
+    // Alice is cached, bob is loaded to learn his slug
+    storage
+        .get_for_data::<Member>()
+        .unwrap()
+        .find_one(&MemberQuery::id(1))
+        .await
+        .unwrap();
+
+    storage
+        .invalidate_cascade::<Member>(&MemberQuery::id(1))
+        .await
+        .unwrap();
+    storage
+        .invalidate_cascade::<Member>(&MemberQuery::id(2))
+        .await
+        .unwrap();
+    teams.find_one(&TeamQuery::id(1)).await.unwrap();
+    teams.find_one(&TeamQuery::id(2)).await.unwrap();
+    assert_eq!(4, teams.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_populate() {
+    let mut storage = DataManager::new();
+    storage.register_storage(members());
+    let team = Team {
+        id: 1,
+        lead: DataRef::new(MemberQuery::slug("alice".into())),
+        deputy: Some(DataRef::new(MemberQuery::id(4))),
+    };
+
+    let references = team.populate(&storage).await;
+    assert_eq!(Some(1), references.lead.map(|lead| lead.id));
+    assert_eq!(Some(None), references.deputy);
+
+    let resolved = datacache::Resolved::new(Data::new(team), &storage).await;
+    assert_eq!(1, resolved.id);
+    assert_eq!(
+        "alice",
+        resolved.references.lead.as_ref().unwrap().slug.as_str()
+    );
+}
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+struct Squad {
+    #[datacache(id)]
+    id: i32,
+    // Picked up as references without the attribute
+    captain: DataRef<Member>,
+    members: Vec<DataRef<Member>>,
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_concurrent_populate() {
+    let mut storage = DataManager::new();
+    storage.register_storage(MemberStorage::new(MemberExecutor {
+        delay: Some(Duration::from_millis(20)),
+        ..MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "users"),
+            Member::new(3, "carol", "admins"),
+        ])
+    }));
+    let squad = Squad {
+        id: 1,
+        captain: DataRef::new(MemberQuery::id(1)),
+        members: vec![
+            DataRef::new(MemberQuery::by_slug("bob")),
+            DataRef::new(MemberQuery::by_slug("carol")),
+        ],
+    };
+    assert_eq!(3, squad.references().len());
+
+    let start = tokio::time::Instant::now();
+    let references = squad.populate(&storage).await;
+    // All lookups wait for the executor at the same time
+    assert_eq!(Duration::from_millis(20), start.elapsed());
+    assert_eq!(Some(1), references.captain.map(|captain| captain.id));
+    assert_eq!(
+        vec![Some(2), Some(3)],
+        references
+            .members
+            .iter()
+            .map(|member| member.as_ref().map(|member| member.id))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[derive(DataMarker)]
+enum Stage {
+    Identification {
+        #[datacache(queryable)]
+        id: i32,
+        #[datacache(queryable)]
+        slug: String,
+    },
+    Password {
+        #[datacache(queryable)]
+        id: i32,
+        #[datacache(queryable)]
+        slug: String,
+        #[allow(dead_code)]
+        backend: String,
+    },
+    Deny(#[datacache(queryable, rename = "deny")] i32),
+    Dummy,
+}
+
+#[test]
+fn test_enum_queries() {
+    let stage = Stage::Password {
+        id: 1,
+        slug: "password".into(),
+        backend: "ldap".into(),
+    };
+    assert_eq!(
+        vec![StageQuery::id(1), StageQuery::slug("password".into())],
+        stage.create_queries()
+    );
+    let stage = Stage::Identification {
+        id: 2,
+        slug: "identification".into(),
+    };
+    assert_eq!(
+        vec![StageQuery::id(2), StageQuery::slug("identification".into())],
+        stage.create_queries()
+    );
+    assert_eq!(vec![StageQuery::deny(3)], Stage::Deny(3).create_queries());
+    assert!(Stage::Dummy.create_queries().is_empty());
+}
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+#[datacache(query(name = "tenant_slug", fields(tenant_id, slug)))]
+struct Account {
+    #[datacache(id)]
+    id: i32,
+    tenant_id: i32,
+    slug: String,
+}
+
+datacache::storage!(
+    AccountStorage(MemoryExecutor<Account>, Account),
+    id(id: i32),
+    unique(tenant_slug: (i32, String)),
+    fields()
+);
+
+#[tokio::test]
+async fn test_composite_query() {
+    let account = |id, tenant_id, slug: &str| Account {
+        id,
+        tenant_id,
+        slug: slug.into(),
+    };
+    assert_eq!(
+        vec![
+            AccountQuery::id(1),
+            AccountQuery::tenant_slug(2, "admin".into())
+        ],
+        account(1, 2, "admin").create_queries()
+    );
+
+    let storage = AccountStorage::new(MemoryExecutor::new(vec![
+        account(1, 1, "admin"),
+        account(2, 2, "admin"),
+    ]));
+    let query = AccountQuery::tenant_slug(2, "admin".into());
+    assert_eq!(2, storage.find_one(&query).await.unwrap().id);
+    assert_eq!(2, storage.find_one(&query).await.unwrap().id);
+    assert_eq!(2, storage.find_one(&AccountQuery::id(2)).await.unwrap().id);
+    assert_eq!(1, storage.get_executor().find_calls.load(Ordering::SeqCst));
+    assert_eq!(1, storage.stats().index_entries);
+}
+
+#[derive(DataMarker)]
+struct Login {
+    #[datacache(queryable, rename = "Id")]
+    id: i32,
+    #[datacache(queryable(with = lowercase, shared), rename = "Email")]
+    email: String,
+    #[datacache(queryable(shared))]
+    name: Arc<str>,
+}
+
+fn lowercase(value: &str) -> String {
+    value.to_lowercase()
+}
+
+#[test]
+fn test_query_options() {
+    let login = Login {
+        id: 1,
+        email: "Alice@Example.com".into(),
+        name: "alice".into(),
+    };
+    assert_eq!(
+        vec![
+            LoginQuery::Id(1),
+            LoginQuery::Email("alice@example.com".into()),
+            LoginQuery::name("alice".into()),
+        ],
+        login.create_queries()
+    );
+    let LoginQuery::name(name) = &login.create_queries()[2] else {
+        unreachable!()
+    };
+    assert!(Arc::ptr_eq(name, &login.name));
+}
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+struct User {
+    #[datacache(id)]
+    id: i32,
+    #[datacache(queryable(unique, normalize = "lowercase"))]
+    email: String,
+    #[datacache(queryable(unique, normalize = "trim"))]
+    username: String,
+    #[datacache(queryable(unique, normalize = strip_at))]
+    handle: String,
+}
+
+fn strip_at(value: &str) -> String {
+    value.trim_start_matches('@').into()
+}
+
+datacache::storage!(
+    UserStorage(MemoryExecutor<User>, User),
+    id(id: i32),
+    unique(email: String, username: String, handle: String),
+    fields()
+);
+
+#[tokio::test]
+async fn test_normalized_queries() {
+    let user = User {
+        id: 1,
+        email: "Alice@Example.com".into(),
+        username: " alice ".into(),
+        handle: "@alice".into(),
+    };
+    assert_eq!(
+        vec![
+            UserQuery::id(1),
+            UserQuery::email("alice@example.com".into()),
+            UserQuery::username("alice".into()),
+            UserQuery::handle("alice".into()),
+        ],
+        user.create_queries()
+    );
+
+    let storage = UserStorage::new(MemoryExecutor::new(vec![user]));
+    let email = UserQuery::email_normalized("ALICE@example.COM");
+    assert_eq!(1, storage.find_one(&email).await.unwrap().id);
+    assert_eq!(
+        1,
+        storage
+            .find_one(&UserQuery::username_normalized("alice  "))
+            .await
+            .unwrap()
+            .id
+    );
+    assert_eq!(
+        1,
+        storage
+            .find_one(&UserQuery::handle_normalized("@alice"))
+            .await
+            .unwrap()
+            .id
+    );
+    assert_eq!(1, storage.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+#[datacache(query(name = "domain_handle", fields(domain, handle)))]
+struct Mailbox {
+    #[datacache(id)]
+    id: i32,
+    #[datacache(queryable(with = lowercase, normalize = "trim"))]
+    domain: String,
+    #[datacache(queryable(normalize = strip_at))]
+    handle: String,
+}
+
+#[test]
+fn test_normalize_pipeline() {
+    let mailbox = Mailbox {
+        id: 1,
+        domain: " Example.COM ".into(),
+        handle: "@alice".into(),
+    };
+    // Composite members go through the same steps as the single field queries
+    assert_eq!(
+        vec![
+            MailboxQuery::id(1),
+            MailboxQuery::domain("example.com".into()),
+            MailboxQuery::handle("alice".into()),
+            MailboxQuery::domain_handle("example.com".into(), "alice".into()),
+        ],
+        mailbox.create_queries()
+    );
+    assert_eq!(
+        MailboxQuery::domain("example.com".into()),
+        MailboxQuery::domain_normalized("EXAMPLE.com  ")
+    );
+    assert_eq!(
+        MailboxQuery::domain("example.com".into()),
+        MailboxQuery::by_domain(" Example.com")
+    );
+    assert_eq!(
+        MailboxQuery::domain_handle("example.com".into(), "alice".into()),
+        MailboxQuery::by_domain_handle("EXAMPLE.COM", "@alice")
+    );
+    // Only the id and composite queries identify a single mailbox
+    assert!(MailboxQuery::by_id(1).is_unique());
+    assert!(!MailboxQuery::by_handle("alice").is_unique());
+    assert!(MailboxQuery::by_domain_handle("example.com", "alice").is_unique());
+}
+
+#[derive(DataMarker)]
+struct Unqueryable {
+    #[allow(dead_code)]
+    id: i32,
+}
+
+#[test]
+fn test_query_helpers() {
+    let query = MemberQuery::by_slug("alice");
+    assert_eq!(MemberQuery::slug("alice".into()), query);
+    assert_eq!("slug", query.field_name());
+    assert_eq!("slug=\"alice\"", query.to_string());
+    assert_eq!(&["id", "slug", "group"], Member::query_fields());
+    assert_eq!("deny=3", StageQuery::by_deny(3).to_string());
+
+    let query = AccountQuery::by_tenant_slug(1, "admin");
+    assert_eq!("tenant_slug=(1, \"admin\")", query.to_string());
+    assert!(query.is_unique());
+    assert!(AccountQuery::by_id(1).is_unique());
+    assert!(MemberQuery::by_slug("alice").is_unique());
+    assert!(!MemberQuery::by_group("admins").is_unique());
+    assert_eq!(&["id", "tenant_slug"], Account::query_fields());
+
+    assert_eq!("email", LoginQuery::Email("a".into()).field_name());
+    assert!(Unqueryable::query_fields().is_empty());
+}
+
+#[derive(DataMarker, Debug, Clone, PartialEq, Eq)]
+struct Tag {
+    #[datacache(id, queryable(shared))]
+    name: Arc<str>,
+    #[datacache(queryable)]
+    color: String,
+}
+
+datacache::storage!(
+    TagStorage(MemoryExecutor<Tag>, Tag),
+    id(name: Arc<str>),
+    unique(),
+    fields(color: String)
+);
+
+#[tokio::test]
+async fn test_non_copy_id() {
+    let tag = |name: &str, color: &str| Tag {
+        name: name.into(),
+        color: color.into(),
+    };
+    let storage = TagStorage::new(MemoryExecutor::new(vec![
+        tag("rust", "orange"),
+        tag("go", "blue"),
+    ]));
+    let orange = TagQuery::by_color("orange");
+    assert_eq!("rust", &*storage.find_one(&orange).await.unwrap().name);
+    assert_eq!(1, storage.find_all(Some(&orange)).await.unwrap().len());
+    storage.update(tag("rust", "red")).await.unwrap();
+    assert_eq!(None, storage.find_optional(&orange).await.unwrap());
+    assert_eq!(
+        "red",
+        storage
+            .find_one(&TagQuery::by_name("rust"))
+            .await
+            .unwrap()
+            .color
+    );
+}
+
+#[test]
+fn test_data_id() {
+    let member = Member::new(1, "alice", "admins");
+    assert_eq!(1, DataId::id(&member));
+    assert_eq!(MemberQuery::id(1), <Member as DataId>::id_query(1));
+    assert_eq!(Some(1), Member::query_id(&MemberQuery::id(1)));
+    assert_eq!(None, Member::query_id(&MemberQuery::by_slug("alice")));
+    assert!(MemberQuery::id(1).is_unique());
+}
+
+async fn find_by_id<S, Exc, D>(storage: &S, id: D::Id) -> Option<Data<D>>
+where
+    S: DataStorage<Exc, D>,
+    Exc: DataQueryExecutor<D, Error = String>,
+    D: DataId,
+{
+    storage.find_optional(&D::id_query(id)).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_generic_storage() {
+    let storage: datacache::Storage<MemberExecutor, Member> =
+        datacache::Storage::new(MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "users"),
+        ]));
+    assert_eq!("alice", find_by_id(&storage, 1).await.unwrap().slug);
+    assert_eq!("bob", find_by_id(&members(), 2).await.unwrap().slug);
+    assert!(find_by_id(&storage, 4).await.is_none());
+    // Without a schema the slug is not indexed, but the query result is still cached
+    let calls = || storage.get_executor().find_calls.load(Ordering::SeqCst);
+    let before = calls();
+    let bob = MemberQuery::by_slug("bob");
+    assert_eq!(2, storage.find_one(&bob).await.unwrap().id);
+    assert_eq!(2, storage.find_one(&bob).await.unwrap().id);
+    assert_eq!(before + 1, calls());
+}
+
+#[tokio::test]
+async fn test_hash_map_backend() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "users"),
+        ]),
+        StorageConfig::new().backend(datacache::BackendKind::HashMap),
+    );
+    storage.find_one(&MemberQuery::id(1)).await.unwrap();
+    storage
+        .find_optional(&MemberQuery::by_slug("dave"))
+        .await
+        .unwrap();
+    // Entries are counted without running any maintenance
+    let stats = storage.stats();
+    assert_eq!(1, stats.data_entries);
+    assert_eq!(1, stats.negative_entries);
+
+    storage.find_one(&MemberQuery::id(1)).await.unwrap();
+    storage
+        .find_optional(&MemberQuery::by_slug("dave"))
+        .await
+        .unwrap();
+    assert_eq!(2, storage.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_hash_map_negative_expiry() {
+    let config = StorageConfig::new().backend(datacache::BackendKind::HashMap);
+    assert_eq!(
+        datacache::DEFAULT_NEGATIVE_TIME_TO_LIVE,
+        config.get_negative_time_to_live()
+    );
+    let storage = MemberStorage::with_config(
+        MemberExecutor::default(),
+        config.negative_time_to_live(Duration::from_millis(20)),
+    );
+    let dave = MemberQuery::by_slug("dave");
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+    assert_eq!(1, storage.stats().negative_entries);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(0, storage.stats().negative_entries);
+    assert_eq!(None, storage.find_optional(&dave).await.unwrap());
+    assert_eq!(2, storage.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_noop_backend() {
+    let storage = UncachedDataStorage::new(MacroExecutor);
+    assert_eq!(
+        datacache::BackendKind::Noop,
+        UncachedDataStorage::default_config().get_backend()
+    );
+    let data = storage.find_one(&MacroDataQuery::id(7)).await.unwrap();
+    assert_eq!(7, data.id);
+    storage.sync();
+    assert_eq!(0, storage.stats().data_entries);
+}
+
+#[tokio::test]
+async fn test_noop_backend_indexes() {
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "admins"),
+        ]),
+        StorageConfig::new()
+            .backend(datacache::BackendKind::Noop)
+            .batch_window(Duration::from_millis(5), tokio::time::sleep),
+    );
+    for _ in 0..100 {
+        storage
+            .find_one(&MemberQuery::by_slug("alice"))
+            .await
+            .unwrap();
+    }
+    let query = MemberQuery::by_group("admins");
+    assert_eq!(2, storage.find_all(Some(&query)).await.unwrap().len());
+    let stats = storage.stats();
+    assert_eq!(0, stats.index_entries);
+    assert_eq!(0, stats.field_index_entries);
+
+    // Nothing resolves the old slug to the renamed entity
+    rename_member(&storage, 1, "alicia");
+    assert_eq!(
+        None,
+        storage
+            .find_optional(&MemberQuery::by_slug("alice"))
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        1,
+        storage
+            .find_one(&MemberQuery::by_slug("alicia"))
+            .await
+            .unwrap()
+            .id
+    );
+}
+
+#[tokio::test]
+async fn test_custom_backends() {
+    use datacache::CacheBackend;
+
+    let data = Arc::new(datacache::HashMapBackend::new());
+    let storage = MemberStorage::with_backends(
+        MemberExecutor::with_members(vec![Member::new(1, "alice", "admins")]),
+        StorageConfig::new(),
+        Arc::clone(&data),
+        datacache::NoopBackend,
+    );
+    storage
+        .find_one(&MemberQuery::by_slug("alice"))
+        .await
+        .unwrap();
+    let entries: Vec<_> = data
+        .iter()
+        .map(|(id, member)| (id, member.slug.clone()))
+        .collect();
+    assert_eq!(vec![(1, "alice".to_string())], entries);
+    storage.invalidate(&MemberQuery::id(1)).await.unwrap();
+    assert!(!data.contains_key(&1));
+}
+
+/// Keeps the latest entity only and evicts the previous one.
+#[derive(Default)]
+struct LatestBackend {
+    entry: Mutex<Option<(i32, Data<Member>)>>,
+    listener: Mutex<Option<datacache::EvictionListener<i32, Data<Member>>>>,
+}
+
+impl datacache::CacheBackend<i32, Data<Member>> for LatestBackend {
+    fn get(&self, key: &i32) -> Option<Data<Member>> {
+        let entry = self.entry.lock().unwrap();
+        entry
+            .as_ref()
+            .filter(|(id, _)| id == key)
+            .map(|(_, data)| data.clone())
+    }
+
+    fn insert(&self, key: i32, value: Data<Member>) {
+        let previous = self.entry.lock().unwrap().replace((key, value));
+        if let Some((id, data)) = previous.filter(|(id, _)| *id != key) {
+            if let Some(listener) = self.listener.lock().unwrap().as_ref() {
+                listener(&id, &data);
+            }
+        }
+    }
+
+    fn invalidate(&self, key: &i32) {
+        let mut entry = self.entry.lock().unwrap();
+        if entry.as_ref().is_some_and(|(id, _)| id == key) {
+            *entry = None;
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (i32, Data<Member>)> + '_> {
+        Box::new(self.entry.lock().unwrap().clone().into_iter())
+    }
+
+    fn set_eviction_listener(&self, listener: datacache::EvictionListener<i32, Data<Member>>) {
+        *self.listener.lock().unwrap() = Some(listener);
+    }
+}
+
+#[tokio::test]
+async fn test_custom_backend_eviction() {
+    let storage = MemberStorage::with_backends(
+        MemberExecutor::with_members(vec![
+            Member::new(1, "alice", "admins"),
+            Member::new(2, "bob", "users"),
+        ]),
+        StorageConfig::new(),
+        LatestBackend::default(),
+        datacache::HashMapBackend::new(),
+    );
+    storage
+        .find_one(&MemberQuery::by_slug("alice"))
+        .await
+        .unwrap();
+    storage
+        .find_one(&MemberQuery::by_slug("bob"))
+        .await
+        .unwrap();
+    let stats = storage.stats();
+    assert_eq!(1, stats.data_entries);
+    assert_eq!(1, stats.evictions);
+    // Only bob is still indexed by slug
+    assert_eq!(1, stats.index_entries);
+}
+
+/// Keeps the last value of every metric, labeled by everything but the storage name.
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct MetricsRecorder {
+    values: Arc<Mutex<std::collections::HashMap<String, f64>>>,
+}
+
+#[cfg(feature = "metrics")]
+struct RecordedMetric {
+    key: String,
+    values: Arc<Mutex<std::collections::HashMap<String, f64>>>,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsRecorder {
+    fn get(&self, key: &str) -> f64 {
+        self.values
+            .lock()
+            .unwrap()
+            .get(key)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn metric(&self, key: &metrics::Key) -> Arc<RecordedMetric> {
+        let labels: Vec<_> = key
+            .labels()
+            .filter(|label| label.key() != "storage")
+            .map(|label| format!("{}={}", label.key(), label.value()))
+            .collect();
+        let name = match labels.is_empty() {
+            true => key.name().to_string(),
+            false => format!("{}{{{}}}", key.name(), labels.join(",")),
+        };
+        Arc::new(RecordedMetric {
+            key: name,
+            values: Arc::clone(&self.values),
+        })
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl RecordedMetric {
+    fn update(&self, update: impl FnOnce(&mut f64)) {
+        update(
+            self.values
+                .lock()
+                .unwrap()
+                .entry(self.key.clone())
+                .or_default(),
+        );
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl metrics::CounterFn for RecordedMetric {
+    fn increment(&self, value: u64) {
+        self.update(|current| *current += value as f64);
+    }
+
+    fn absolute(&self, value: u64) {
+        self.update(|current| *current = value as f64);
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl metrics::GaugeFn for RecordedMetric {
+    fn increment(&self, value: f64) {
+        self.update(|current| *current += value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| *current -= value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|current| *current = value);
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl metrics::Recorder for MetricsRecorder {
+    fn describe_counter(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+
+    fn describe_gauge(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+
+    fn describe_histogram(
+        &self,
+        _: metrics::KeyName,
+        _: Option<metrics::Unit>,
+        _: metrics::SharedString,
+    ) {
+    }
+
+    fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
+        metrics::Counter::from_arc(self.metric(key))
+    }
+
+    fn register_gauge(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
+        metrics::Gauge::from_arc(self.metric(key))
+    }
+
+    fn register_histogram(
+        &self,
+        _: &metrics::Key,
+        _: &metrics::Metadata<'_>,
+    ) -> metrics::Histogram {
+        metrics::Histogram::noop()
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics() {
+    let recorder = MetricsRecorder::default();
+    metrics::with_local_recorder(&recorder, || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let storage = MemberStorage::with_backends(
+                MemberExecutor::with_members(vec![
+                    Member::new(1, "alice", "admins"),
+                    Member::new(2, "bob", "users"),
+                ]),
+                StorageConfig::new().backend(datacache::BackendKind::HashMap),
+                LatestBackend::default(),
+                datacache::HashMapBackend::new(),
+            );
+            // The reporter refreshes the gauges every 10ms
+            let reporter = tokio::spawn(
+                storage.metrics_reporter(Duration::from_millis(10), tokio::time::sleep),
+            );
+            let tick = || tokio::time::sleep(Duration::from_millis(15));
+            storage
+                .find_one(&MemberQuery::by_slug("alice"))
+                .await
+                .unwrap();
+            tick().await;
+            assert_eq!(1.0, recorder.get("datacache_entries{cache=data}"));
+            assert_eq!(1.0, recorder.get("datacache_entries{cache=index}"));
+
+            storage
+                .find_one(&MemberQuery::by_slug("bob"))
+                .await
+                .unwrap();
+            tick().await;
+            assert_eq!(1.0, recorder.get("datacache_evictions_total"));
+            assert_eq!(1.0, recorder.get("datacache_entries{cache=data}"));
+            assert_eq!(1.0, recorder.get("datacache_entries{cache=index}"));
+
+            storage
+                .find_optional(&MemberQuery::by_slug("dave"))
+                .await
+                .unwrap();
+            tick().await;
+            assert_eq!(1.0, recorder.get("datacache_entries{cache=negative}"));
+
+            storage.delete(&MemberQuery::by_slug("bob")).await.unwrap();
+            tick().await;
+            assert_eq!(0.0, recorder.get("datacache_entries{cache=data}"));
+            assert_eq!(0.0, recorder.get("datacache_entries{cache=index}"));
+            assert_eq!(3.0, recorder.get("datacache_misses_total"));
+            assert_eq!(4.0, recorder.get("datacache_executor_calls_total"));
+
+            drop(storage);
+            reporter.await.unwrap();
+        });
+    });
+}
+
+/// Keeps the fields and the parent of every span.
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct SpanRecorder {
+    spans: Mutex<Vec<RecordedSpan>>,
+    stack: Mutex<Vec<u64>>,
+}
+
+#[cfg(feature = "tracing")]
+#[derive(Clone, Debug)]
+struct RecordedSpan {
+    metadata: &'static tracing::Metadata<'static>,
+    parent: Option<u64>,
+    fields: std::collections::HashMap<&'static str, String>,
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for RecordedSpan {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.fields.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn Debug) {
+        self.fields.insert(field.name(), format!("{value:?}"));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl SpanRecorder {
+    fn spans(&self, method: &str) -> Vec<RecordedSpan> {
+        self.spans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|span| span.fields.get("method").map(String::as_str) == Some(method))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let parent = if attrs.is_contextual() {
+            self.stack.lock().unwrap().last().copied()
+        } else {
+            attrs.parent().map(tracing::span::Id::into_u64)
+        };
+        let mut span = RecordedSpan {
+            metadata: attrs.metadata(),
+            parent,
+            fields: Default::default(),
+        };
+        attrs.record(&mut span);
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(span);
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        values.record(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1]);
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event<'_>) {}
+
+    fn enter(&self, span: &tracing::span::Id) {
+        self.stack.lock().unwrap().push(span.into_u64());
+    }
+
+    fn exit(&self, _: &tracing::span::Id) {
+        self.stack.lock().unwrap().pop();
+    }
+
+    fn current_span(&self) -> tracing_core::span::Current {
+        match self.stack.lock().unwrap().last() {
+            Some(&id) => tracing_core::span::Current::new(
+                tracing::span::Id::from_u64(id),
+                self.spans.lock().unwrap()[id as usize - 1].metadata,
+            ),
+            None => tracing_core::span::Current::none(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    let recorder = Arc::new(SpanRecorder::default());
+    let _guard = tracing::subscriber::set_default(recorder.clone());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let storage = MemberStorage::with_config(
+            MemberExecutor::with_members(vec![
+                Member::new(1, "alice", "admins"),
+                Member::new(2, "bob", "users"),
+                Member::new(3, "carol", "admins"),
+            ]),
+            StorageConfig::new().batch_window(Duration::from_millis(5), tokio::time::sleep),
+        );
+        storage
+            .find_optional(&MemberQuery::by_slug("alice"))
+            .await
+            .unwrap();
+        storage.find_optional(&MemberQuery::id(1)).await.unwrap();
+        let lookups = recorder.spans("find_optional");
+        // Only the name of the query is recorded, never its values
+        assert_eq!("slug", lookups[0].fields["query"]);
+        assert_eq!("miss", lookups[0].fields["cache"]);
+        assert!(lookups[0].fields.contains_key("executor_latency_us"));
+        assert_eq!("id", lookups[1].fields["query"]);
+        assert_eq!("hit", lookups[1].fields["cache"]);
+
+        // The batch load gets a span of its own instead of the caller's
+        storage.find_optional(&MemberQuery::id(2)).await.unwrap();
+        let lookup = &recorder.spans("find_optional")[2];
+        assert_eq!("miss", lookup.fields["cache"]);
+        assert!(!lookup.fields.contains_key("executor_latency_us"));
+        let batch = &recorder.spans("batch_load")[0];
+        assert_eq!(None, batch.parent);
+        assert_eq!("1", batch.fields["keys"]);
+        assert!(batch.fields.contains_key("executor_latency_us"));
+
+        storage.find_all(None).await.unwrap();
+        assert_eq!("partial", recorder.spans("find_all")[0].fields["cache"]);
+    });
+}
+
+#[tokio::test]
+async fn test_shared_pending_lookups() {
+    let storage = MemberStorage::new(MemberExecutor {
+        members: Arc::new(Mutex::new(vec![Member::new(1, "alice", "admins")])),
+        delay: Some(Duration::from_millis(20)),
+        ..Default::default()
+    });
+    let alice = MemberQuery::by_slug("alice");
+    let (first, second) = tokio::join!(storage.find_one(&alice), storage.find_one(&alice));
+    assert_eq!(first.unwrap(), second.unwrap());
+    assert_eq!(1, storage.get_executor().find_calls.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_l2_cache() {
+    use datacache::L2Cache;
+
+    let l2 = Arc::new(datacache::MemoryL2Cache::new());
+    let warm = MemberStorage::new(MemberExecutor::with_members(vec![
+        Member::new(1, "alice", "admins"),
+        Member::new(2, "bob", "users"),
+    ]))
+    .with_l2_cache(l2.clone(), "members");
+    let alice = MemberQuery::by_slug("alice");
+    warm.find_one(&alice).await.unwrap();
+    // Stored under the slug and the id query
+    assert_eq!(2, l2.len());
+    assert!(l2.get(r#"members:{"id":1}"#).await.is_some());
+
+    // A cold replica without any entities is served by the second level
+    let cold = MemberStorage::new(MemberExecutor::default()).with_l2_cache(l2.clone(), "members");
+    assert_eq!(
+        "alice",
+        cold.find_one(&MemberQuery::id(1)).await.unwrap().slug
+    );
+    assert_eq!(1, cold.find_one(&alice).await.unwrap().id);
+    assert_eq!(0, cold.get_executor().find_calls.load(Ordering::SeqCst));
+
+    warm.update(Member::new(1, "alicia", "admins"))
+        .await
+        .unwrap();
+    assert_eq!(1, l2.len());
+    let cold = MemberStorage::new(MemberExecutor::default()).with_l2_cache(l2.clone(), "members");
+    assert_eq!(None, cold.find_optional(&alice).await.unwrap());
+    assert_eq!(
+        "alicia",
+        cold.find_one(&MemberQuery::id(1)).await.unwrap().slug
+    );
+
+    warm.invalidate(&MemberQuery::id(1)).await.unwrap();
+    assert!(l2.is_empty());
+    warm.find_one(&MemberQuery::id(2)).await.unwrap();
+    warm.delete(&MemberQuery::id(2)).await.unwrap();
+    assert!(l2.is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_l2_cache_batched() {
+    let l2 = Arc::new(datacache::MemoryL2Cache::new());
+    let config = || StorageConfig::new().batch_window(Duration::from_millis(5), tokio::time::sleep);
+    let warm = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![Member::new(1, "alice", "admins")]),
+        config(),
+    )
+    .with_l2_cache(l2.clone(), "members");
+    warm.find_one(&MemberQuery::id(1)).await.unwrap();
+    assert_eq!(1, l2.len());
+
+    let cold = MemberStorage::with_config(MemberExecutor::default(), config())
+        .with_l2_cache(l2.clone(), "members");
+    assert_eq!(
+        "alice",
+        cold.find_one(&MemberQuery::id(1)).await.unwrap().slug
+    );
+    assert_eq!(
+        0,
+        cold.get_executor().find_many_calls.load(Ordering::SeqCst)
+    );
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_l2_cache_remote_delete() {
+    let l2 = Arc::new(datacache::MemoryL2Cache::new());
+    let warm = members().with_l2_cache(l2.clone(), "members");
+    warm.find_one(&MemberQuery::by_slug("bob")).await.unwrap();
+    assert_eq!(2, l2.len());
+
+    // Bob is not cached by the replica, his slug is taken from the second level
+    let replica = MemberStorage::new(MemberExecutor::sharing(warm.get_executor()))
+        .with_l2_cache(l2.clone(), "members");
+    replica.delete(&MemberQuery::id(2)).await.unwrap();
+    assert!(l2.is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_l2_cache_time_to_live() {
+    let l2 = Arc::new(datacache::MemoryL2Cache::new());
+    let storage = MemberStorage::with_config(
+        MemberExecutor::with_members(vec![Member::new(1, "alice", "admins")]),
+        StorageConfig::new().time_to_live(Duration::from_millis(20)),
+    )
+    .with_l2_cache(l2.clone(), "members");
+    storage.find_one(&MemberQuery::id(1)).await.unwrap();
+    assert_eq!(1, l2.len());
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(l2.is_empty());
+}
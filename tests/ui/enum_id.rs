@@ -0,0 +1,16 @@
+use datacache::{DataId, DataMarker};
+
+#[derive(DataMarker)]
+enum Stage {
+    Identification {
+        #[datacache(id)]
+        id: i32,
+    },
+    Deny(#[datacache(id, rename = "id")] i32),
+}
+
+fn main() {
+    assert_eq!(1, Stage::Identification { id: 1 }.id());
+    assert_eq!(2, Stage::Deny(2).id());
+    assert_eq!(Some(2), Stage::query_id(&StageQuery::id(2)));
+}
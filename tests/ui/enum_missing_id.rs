@@ -0,0 +1,12 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+enum Stage {
+    Identification {
+        #[datacache(id)]
+        id: i32,
+    },
+    Deny,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+enum Stage {
+    Identification(#[datacache(queryable)] i32),
+    Deny(#[datacache(queryable)] String),
+}
+
+fn main() {}
@@ -0,0 +1,21 @@
+use datacache::DataMarker;
+
+type Slug = String;
+
+#[derive(DataMarker)]
+enum Stage {
+    Identification {
+        #[datacache(queryable)]
+        id: i32,
+        #[datacache(queryable)]
+        slug: Slug,
+    },
+    Password {
+        #[datacache(queryable)]
+        id: i64,
+        #[datacache(queryable)]
+        slug: String,
+    },
+}
+
+fn main() {}
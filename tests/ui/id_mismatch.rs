@@ -0,0 +1,34 @@
+use datacache::{DataMarker, DataQueryExecutor};
+
+#[derive(DataMarker)]
+struct Member {
+    #[datacache(id)]
+    id: i32,
+}
+
+struct MemberExecutor;
+
+#[datacache::__internal::async_trait]
+impl DataQueryExecutor<Member> for MemberExecutor {
+    type Error = String;
+    type Id = String;
+    fn get_id(&self, _: &Member) -> String {
+        todo!()
+    }
+    async fn find_one(&self, _: &MemberQuery) -> Result<Member, String> {
+        todo!()
+    }
+    async fn find_all_ids(&self, _: Option<&MemberQuery>) -> Result<Vec<String>, String> {
+        todo!()
+    }
+    async fn find_optional(&self, _: &MemberQuery) -> Result<Option<Member>, String> {
+        todo!()
+    }
+    async fn delete(&self, _: &MemberQuery) -> Result<Vec<String>, String> {
+        todo!()
+    }
+}
+
+datacache::storage!(MemberStorage(MemberExecutor, Member), unique(), fields());
+
+fn main() {}
@@ -0,0 +1,11 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+struct Member {
+    #[datacache(queryable)]
+    id: i32,
+    #[datacache(queryable, rename = "by_id")]
+    slug: String,
+}
+
+fn main() {}
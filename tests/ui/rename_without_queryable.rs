@@ -0,0 +1,9 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+struct Member {
+    #[datacache(rename = "Id")]
+    id: i32,
+}
+
+fn main() {}
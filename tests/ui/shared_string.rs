@@ -0,0 +1,11 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+struct Member {
+    #[datacache(id)]
+    id: i32,
+    #[datacache(queryable(shared))]
+    slug: String,
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+struct Pair(#[datacache(queryable)] i32, String, #[datacache(queryable)] String);
+
+fn main() {
+    let pair = Pair(1, "ignored".into(), "slug".into());
+    assert_eq!(
+        vec![PairQuery::F0(1), PairQuery::F2("slug".into())],
+        pair.create_queries()
+    );
+    let _ = pair.1;
+}
@@ -0,0 +1,9 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+union Value {
+    int: i32,
+    float: f32,
+}
+
+fn main() {}
@@ -0,0 +1,49 @@
+use std::convert::Infallible;
+
+use datacache::{DataMarker, DataQueryExecutor};
+
+#[derive(DataMarker, Clone)]
+struct Member {
+    #[datacache(id)]
+    id: i32,
+    #[datacache(queryable)]
+    slug: String,
+    #[datacache(queryable(unique))]
+    email: String,
+}
+
+struct MemberExecutor;
+
+#[datacache::__internal::async_trait]
+impl DataQueryExecutor<Member> for MemberExecutor {
+    type Error = Infallible;
+    type Id = i32;
+
+    fn get_id(&self, data: &Member) -> i32 {
+        data.id
+    }
+
+    async fn find_one(&self, _query: &MemberQuery) -> Result<Member, Infallible> {
+        unimplemented!()
+    }
+
+    async fn find_all_ids(&self, _query: Option<&MemberQuery>) -> Result<Vec<i32>, Infallible> {
+        Ok(vec![])
+    }
+
+    async fn find_optional(&self, _query: &MemberQuery) -> Result<Option<Member>, Infallible> {
+        Ok(None)
+    }
+
+    async fn delete(&self, _query: &MemberQuery) -> Result<Vec<i32>, Infallible> {
+        Ok(vec![])
+    }
+}
+
+datacache::storage!(
+    MemberStorage(MemberExecutor, Member),
+    unique(slug: String),
+    fields(email: String)
+);
+
+fn main() {}
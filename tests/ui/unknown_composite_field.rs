@@ -0,0 +1,11 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+#[datacache(query(name = "tenant_slug", fields(tenant_id, slug)))]
+struct Account {
+    #[datacache(queryable)]
+    id: i32,
+    slug: String,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use datacache::DataMarker;
+
+#[derive(DataMarker)]
+struct Member {
+    #[datacache(searchable)]
+    id: i32,
+}
+
+fn main() {}